@@ -1,17 +1,29 @@
 // src/main.rs
 
 use actix_files as fs;
-use actix_multipart::Multipart;
+use actix_multipart::{Field, Multipart};
 use actix_web::{
-    web, App, HttpResponse, HttpServer, Responder, middleware, Error,
+    body::MessageBody, cookie::{Cookie, SameSite}, dev::{Payload, Service, ServiceFactory, ServiceRequest, ServiceResponse},
+    error, http::header, web, App,
+    FromRequest, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder, ResponseError, middleware, Error,
 };
+use askama::Template;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use sled::Db;
-use std::sync::Arc;
-use log::{error, info};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::{error, info, warn};
+use futures_util::future::{self, Either};
 use futures_util::stream::StreamExt;
 use std::io::Write;
+use tokio::sync::broadcast;
+use sha2::{Digest, Sha256};
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
 use uuid::Uuid;
 use html_escape::encode_safe; // For HTML escaping
 use mime_guess::mime; // Import mime constants for media type detection
@@ -21,6 +33,7 @@ use mime_guess::mime; // Import mime constants for media type detection
 enum MediaType {
     Image,
     Video,
+    Audio,
 }
 
 // Update the Thread struct to include media information
@@ -30,8 +43,36 @@ struct Thread {
     title: String,
     message: String,
     last_updated: i64, // Unix timestamp
+    #[serde(default)]
+    created_at: i64, // Unix timestamp, 0 for posts made before it was recorded
+    #[serde(default)]
+    name: Option<String>, // Poster name, without the tripcode secret
+    #[serde(default)]
+    tripcode: Option<String>, // Hash of the poster's secret, e.g. !3f2a9c01bd
     media_url: Option<String>, // URL to image or video
     media_type: Option<MediaType>, // Type of media: Image or Video
+    #[serde(default)]
+    thumb_url: Option<String>, // Preview image for videos and animated images
+    #[serde(default)]
+    spoiler: bool, // Image is hidden until clicked
+    #[serde(default)]
+    media_info: Option<MediaInfo>, // Image dimensions and size, missing on older posts
+    #[serde(default)]
+    sticky: bool, // Pinned above all other threads
+    #[serde(default)]
+    locked: bool, // Closed to new replies
+    #[serde(default)]
+    inactive_lock: bool, // Locked by the inactivity job, cleared when the thread is bumped again
+    #[serde(default)]
+    deleted: bool, // Shown as a placeholder until an admin purges it
+    #[serde(default)]
+    poster_id: Option<String>, // Per-thread ID of the poster's IP, changes daily
+    #[serde(default)]
+    country: Option<String>, // Country code of the poster's IP, only when GEOIP_DB is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    delete_password_hash: Option<String>, // Argon2 hash of the poster's deletion password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    creator_hash: Option<String>, // Salted hash of the creator's IP, counts their open threads
 }
 
 // Define Reply struct
@@ -39,6 +80,378 @@ struct Thread {
 struct Reply {
     id: i32,
     message: String,
+    #[serde(default)]
+    created_at: i64, // Unix timestamp, 0 for posts made before it was recorded
+    #[serde(default)]
+    name: Option<String>, // Poster name, without the tripcode secret
+    #[serde(default)]
+    tripcode: Option<String>, // Hash of the poster's secret, e.g. !3f2a9c01bd
+    #[serde(default)]
+    media_url: Option<String>, // URL to image or video
+    #[serde(default)]
+    media_type: Option<MediaType>, // Type of media: Image or Video
+    #[serde(default)]
+    thumb_url: Option<String>, // Preview image for videos and animated images
+    #[serde(default)]
+    spoiler: bool, // Image is hidden until clicked
+    #[serde(default)]
+    media_info: Option<MediaInfo>, // Image dimensions and size, missing on older posts
+    #[serde(default)]
+    poster_id: Option<String>, // Per-thread ID of the poster's IP, changes daily
+    #[serde(default)]
+    country: Option<String>, // Country code of the poster's IP, only when GEOIP_DB is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    delete_password_hash: Option<String>, // Argon2 hash of the poster's deletion password
+    #[serde(default)]
+    deleted: bool, // Shown as a placeholder until an admin purges it
+}
+
+impl Thread {
+    // Blank out everything a deleted thread showed, leaving the tombstone
+    fn redact_if_deleted(&mut self) {
+        if self.deleted {
+            self.title = DELETED_PLACEHOLDER.to_string();
+            self.message = DELETED_PLACEHOLDER.to_string();
+            self.name = None;
+            self.tripcode = None;
+            self.media_url = None;
+            self.media_type = None;
+            self.thumb_url = None;
+            self.spoiler = false;
+            self.media_info = None;
+            self.poster_id = None;
+            self.country = None;
+        }
+    }
+}
+
+impl From<Thread> for ApiThread {
+    // Never hand out password or IP hashes, or the content of deleted posts
+    fn from(mut thread: Thread) -> Self {
+        thread.delete_password_hash = None;
+        thread.creator_hash = None;
+        thread.redact_if_deleted();
+        // Threads made before creation times were recorded date from their last bump, as on the pages
+        if thread.created_at == 0 {
+            thread.created_at = thread.last_updated;
+        }
+        ApiThread { updated_at: thread.last_updated, thread }
+    }
+}
+
+impl Reply {
+    // Blank out everything a deleted reply showed, leaving the tombstone
+    fn redact_if_deleted(&mut self) {
+        if self.deleted {
+            self.message = DELETED_PLACEHOLDER.to_string();
+            self.name = None;
+            self.tripcode = None;
+            self.media_url = None;
+            self.media_type = None;
+            self.thumb_url = None;
+            self.spoiler = false;
+            self.media_info = None;
+            self.poster_id = None;
+            self.country = None;
+        }
+    }
+}
+
+// Define a thread about to be validated and stored, as read from the HTML form or the JSON API
+struct NewThread {
+    board: String, // Slug as submitted, checked against the allow-list
+    name: String, // Name as typed, possibly with a #tripcode secret
+    title: String,
+    message: String,
+    delete_password: String,
+    spoiler: bool,
+    media_url: Option<String>, // Upload already saved to disk, deleted if the thread is refused
+    media_type: Option<MediaType>,
+    thumb_url: Option<String>,
+    media_info: Option<MediaInfo>,
+    ip: String, // Client address, only stored as poster and creator hashes
+    country: Option<String>, // Looked up from the address by the caller
+}
+
+// Define a reply about to be validated and stored
+struct NewReply {
+    board: String, // Slug as submitted, checked against the allow-list
+    parent_id: i32,
+    name: String, // Name as typed, possibly with a #tripcode secret
+    message: String,
+    delete_password: String,
+    spoiler: bool,
+    sage: bool, // Leaves the thread where it is instead of bumping it
+    media_url: Option<String>, // Upload already saved to disk, deleted if the reply is refused
+    media_type: Option<MediaType>,
+    thumb_url: Option<String>,
+    media_info: Option<MediaInfo>,
+    ip: String, // Client address, only stored as a poster hash
+    country: Option<String>, // Looked up from the address by the caller
+}
+
+// Define the ways a request can be refused, kept apart from HTTP so the logic can be
+// tested on its own. Each is answered with an error page and a matching status code.
+#[derive(Debug)]
+enum BoardError {
+    NotFound(&'static str, String), // Page title and message
+    BadRequest(String),
+    Unauthorized(String), // Admin login required or wrong
+    Forbidden(&'static str, String), // Page title and message
+    TooLarge(String),
+    RateLimited {
+        title: &'static str,
+        message: String,
+        retry_after: Option<u64>, // Seconds until the client may try again
+    },
+    Unavailable(&'static str, String), // Page title and message, posting is paused
+    Internal(String), // Details are logged where the failure happens
+}
+
+impl std::fmt::Display for BoardError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BoardError::NotFound(_, message)
+            | BoardError::BadRequest(message)
+            | BoardError::Unauthorized(message)
+            | BoardError::Forbidden(_, message)
+            | BoardError::TooLarge(message)
+            | BoardError::RateLimited { message, .. }
+            | BoardError::Unavailable(_, message)
+            | BoardError::Internal(message) => f.write_str(message),
+        }
+    }
+}
+
+impl ResponseError for BoardError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        use actix_web::http::StatusCode;
+        match self {
+            BoardError::NotFound(..) => StatusCode::NOT_FOUND,
+            BoardError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            BoardError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            BoardError::Forbidden(..) => StatusCode::FORBIDDEN,
+            BoardError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            BoardError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            BoardError::Unavailable(..) => StatusCode::SERVICE_UNAVAILABLE,
+            BoardError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        let page = match self {
+            BoardError::NotFound(title, message)
+            | BoardError::Forbidden(title, message)
+            | BoardError::Unavailable(title, message) => render_error_page(title, message),
+            BoardError::BadRequest(message) => render_error_page("Bad Request", message),
+            BoardError::Unauthorized(message) => {
+                response.append_header((header::WWW_AUTHENTICATE, "Bearer"));
+                render_error_page("Unauthorized", message)
+            }
+            BoardError::TooLarge(message) => render_error_page("Payload Too Large", message),
+            BoardError::RateLimited { title, message, retry_after } => {
+                if let Some(retry_after) = retry_after {
+                    response.append_header((header::RETRY_AFTER, retry_after.to_string()));
+                }
+                render_error_page(title, message)
+            }
+            BoardError::Internal(message) => render_server_error_page(message),
+        };
+        response.content_type("text/html").body(page)
+    }
+}
+
+// The blocking pool only fails when the server is shutting down
+impl From<error::BlockingError> for BoardError {
+    fn from(e: error::BlockingError) -> Self {
+        error!("Blocking task failed: {}", e);
+        BoardError::Internal("The server is shutting down".to_string())
+    }
+}
+
+// Define the body accepted by the JSON API for new threads
+#[derive(Deserialize)]
+struct ApiThreadForm {
+    #[serde(default)]
+    board: String, // The main board when empty
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    delete_password: String,
+    #[serde(default)]
+    captcha: String, // Answer to the question from /api/captcha
+}
+
+// Define the result of storing an uploaded media file
+#[derive(Serialize, Deserialize)]
+struct SavedMedia {
+    url: String, // URL shown in the post
+    media_type: MediaType,
+    thumb_url: Option<String>, // Preview image for videos and animated images
+    #[serde(default)]
+    info: Option<MediaInfo>, // Dimensions and size of images
+}
+
+// Define an upload saved while a post's form is read. Its files are deleted again when it is
+// dropped, so any error or dropped connection before the post is stored cleans them up.
+struct PendingUpload<'a> {
+    db: &'a Db,
+    paths: &'a DataPaths,
+    media_url: Option<String>,
+    thumb_url: Option<String>,
+}
+
+impl<'a> PendingUpload<'a> {
+    fn new(db: &'a Db, paths: &'a DataPaths) -> Self {
+        PendingUpload { db, paths, media_url: None, thumb_url: None }
+    }
+
+    // Keep the files, as the stored post now shows them
+    fn keep(mut self) {
+        self.media_url = None;
+        self.thumb_url = None;
+    }
+}
+
+impl Drop for PendingUpload<'_> {
+    fn drop(&mut self) {
+        discard_upload(self.db, self.paths, &self.media_url, &self.thumb_url);
+    }
+}
+
+// Define the details shown beneath an uploaded image
+#[derive(Serialize, Deserialize, Clone)]
+struct MediaInfo {
+    width: u32,
+    height: u32,
+    file_size: u64, // Bytes, after metadata stripping
+}
+
+// Define a ban on a client IP, stored under ban_{ip}
+#[derive(Serialize, Deserialize)]
+struct Ban {
+    ip: String,
+    reason: String,
+    expires_at: i64, // Unix timestamp after which the ban no longer applies
+}
+
+// Define the fields of the admin ban form
+#[derive(Deserialize)]
+struct BanForm {
+    ip: String,
+    #[serde(default)]
+    reason: String,
+    hours: i64, // Ban length
+}
+
+// Define the fields of the admin unban form
+#[derive(Deserialize)]
+struct UnbanForm {
+    ip: String,
+}
+
+// Define a user report against a post, stored under report_{thread}_{reply}
+#[derive(Serialize, Deserialize)]
+struct Report {
+    board: String,
+    thread_id: i32,
+    reply_id: i32, // 0 when the opening post is reported
+    reason: String,
+    created_at: i64, // Unix timestamp
+}
+
+// Define the fields of the report form shown under each post
+#[derive(Deserialize)]
+struct ReportForm {
+    board: String,
+    thread_id: i32,
+    reply_id: i32,
+    reason: String,
+}
+
+// Define the fields of the form posters use to delete their own post
+#[derive(Deserialize)]
+struct DeletePostForm {
+    board: String,
+    thread_id: i32,
+    reply_id: i32, // 0 for the opening post
+    password: String,
+}
+
+// Define the post an admin report or restore action applies to
+#[derive(Deserialize)]
+struct ReportTarget {
+    board: String,
+    thread_id: i32,
+    reply_id: i32,
+}
+
+// Define the JSON shape of a thread, with both of its timestamps under consistent names
+#[derive(Serialize)]
+struct ApiThread {
+    #[serde(flatten)]
+    thread: Thread,
+    updated_at: i64, // Unix timestamp of the last bump, the same as last_updated
+}
+
+// Define the JSON shape of a thread together with its replies
+#[derive(Serialize)]
+struct ThreadWithReplies {
+    thread: ApiThread,
+    replies: Vec<Reply>,
+}
+
+// Define the JSON shape of a page of threads
+#[derive(Serialize)]
+struct ThreadPage {
+    threads: Vec<ApiThread>,
+    pagination: PaginationInfo,
+}
+
+// Define the pagination details returned alongside a page of threads
+#[derive(Serialize)]
+struct PaginationInfo {
+    total_threads: usize,
+    page: i32, // Clamped to the pages that exist
+    total_pages: i32,
+    page_size: i32,
+}
+
+// Define the JSON backup of every board produced by /admin/export and read by /admin/import
+#[derive(Deserialize)]
+struct BoardDump {
+    threads: Vec<DumpedThread>,
+}
+
+// Define one thread of a board dump along with its board and replies
+#[derive(Serialize, Deserialize)]
+struct DumpedThread {
+    board: String, // Board slug, empty for the main board
+    thread: Thread,
+    replies: Vec<Reply>,
+}
+
+// Define search parameters
+#[derive(Deserialize)]
+struct SearchParams {
+    q: Option<String>,
+}
+
+// Define a borrowed view of a thread or reply for searching
+enum SearchPost<'a> {
+    Thread(&'a Thread),
+    Reply { thread_id: i32, reply: &'a Reply },
+}
+
+// Define which replies a thread view shows
+enum ReplyView {
+    Page(Option<i32>), // A page of REPLIES_PER_PAGE replies
+    Last(usize),       // Only the most recent replies
 }
 
 // Define pagination parameters
@@ -47,641 +460,6869 @@ struct PaginationParams {
     page: Option<i32>,
 }
 
-// Define reply form
+// Define an entry in the page links of pagination controls
+#[derive(Debug, PartialEq)]
+enum PageItem {
+    Page(i32), // Link to a page, or the current page
+    Gap, // Ellipsis standing in for skipped pages
+}
+
+// Define the query parameters of a thread page
 #[derive(Deserialize)]
-struct ReplyForm {
-    parent_id: i32,
-    message: String,
+struct ThreadViewParams {
+    page: Option<i32>,
+    after: Option<i32>, // Last reply ID the reader saw, marks where new replies start
+    quote: Option<i32>, // Reply ID to pre-fill the reply form with a quote of
 }
 
-// Define constants for directories
-const IMAGE_UPLOAD_DIR: &str = "./uploads/images/";
-const VIDEO_UPLOAD_DIR: &str = "./uploads/videos/";
-const IMAGE_THUMB_DIR: &str = "./thumbs/images/";
+// Define the query parameters of a board index page
+#[derive(Deserialize)]
+struct BoardIndexParams {
+    page: Option<i32>,
+    sort: Option<String>, // "bump" (default) or "new"
+}
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    // Initialize the logger
-    env_logger::init();
+// Define the orders threads can be listed in on a board index
+#[derive(Clone, Copy, PartialEq)]
+enum ThreadSort {
+    Bump, // Most recently bumped first
+    New,  // Most recently created first
+}
 
-    // Ensure the uploads and thumbnails directories exist
-    for dir in &[IMAGE_UPLOAD_DIR, VIDEO_UPLOAD_DIR, IMAGE_THUMB_DIR] {
-        if !std::path::Path::new(dir).exists() {
-            std::fs::create_dir_all(dir).unwrap();
-            info!("Created directory: {}", dir);
+impl ThreadSort {
+    // Unknown values fall back to bump order instead of failing the request
+    fn from_query(sort: Option<&str>) -> Self {
+        match sort {
+            Some("new") => ThreadSort::New,
+            _ => ThreadSort::Bump,
         }
     }
+}
 
-    // Initialize the Sled database
-    let sled_db = Arc::new(sled::open("sled_db").expect("Failed to open sled database"));
+// Directory holding the database and media when DATA_DIR is unset
+const DEFAULT_DATA_DIR: &str = ".";
 
-    // Start the Actix-web server
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(sled_db.clone()))
-            .wrap(middleware::Logger::default())
-            .service(fs::Files::new("/static", "./static")) // Disabled directory listing
-            .service(fs::Files::new("/uploads/images", IMAGE_UPLOAD_DIR)) // Serve uploaded images
-            .service(fs::Files::new("/uploads/videos", VIDEO_UPLOAD_DIR)) // Serve uploaded videos
-            .service(fs::Files::new("/thumbs/images", IMAGE_THUMB_DIR)) // Serve image thumbnails
-            .route("/", web::get().to(homepage))
-            .route("/thread/{id}", web::get().to(view_thread))
-            .route("/thread", web::post().to(create_thread))
-            .route("/reply", web::post().to(create_reply))
-    })
-    .bind(("0.0.0.0", 8080))?
-    .run()
-    .await
+// Content-Security-Policy sent with every response. Pages only load scripts and media from this
+// origin, and an upload a browser mistakes for a page still cannot run scripts. Inline styles
+// are allowed for poster ID colors, and remote images for an external BOARD_BANNER.
+const CONTENT_SECURITY_POLICY: &str = "default-src 'self'; script-src 'self'; style-src 'self' 'unsafe-inline'; img-src 'self' data: https:; media-src 'self'; object-src 'none'; base-uri 'self'; form-action 'self'; frame-ancestors 'none'";
+
+// Content-Security-Policy sent with uploaded media and thumbnails instead of the page policy.
+// The sandbox turns any file a browser renders as a document into an inert, script-less page.
+const MEDIA_CONTENT_SECURITY_POLICY: &str = "sandbox; default-src 'none'; img-src 'self'; media-src 'self'; style-src 'unsafe-inline'";
+
+// Cache-Control sent with uploaded media and thumbnails
+const MEDIA_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+// Upload size limits, checked while the file is streamed to disk
+const MAX_IMAGE_UPLOAD_BYTES: usize = 10 * 1024 * 1024;
+const MAX_VIDEO_UPLOAD_BYTES: usize = 50 * 1024 * 1024;
+const MAX_AUDIO_UPLOAD_BYTES: usize = 20 * 1024 * 1024;
+
+// Largest image accepted in pixels (width times height), so a small file cannot decode to gigabytes
+const MAX_IMAGE_PIXELS: u64 = 50_000_000;
+
+// Whole request bodies larger than this are rejected before they are read
+const MAX_REQUEST_BYTES: usize = MAX_VIDEO_UPLOAD_BYTES + 1024 * 1024;
+
+// Thumbnail shown for videos when ffmpeg can't extract a frame
+const VIDEO_PLACEHOLDER_THUMB: &str = "/static/video_placeholder.svg";
+
+// Generic thumbnail shown in place of spoilered images on board pages
+const SPOILER_THUMB: &str = "/static/spoiler.svg";
+
+// Replies past this count no longer bump a thread to the top of the board
+const BUMP_LIMIT: i32 = 300;
+
+// Shown in place of the subject and message of deleted posts
+const DELETED_PLACEHOLDER: &str = "[deleted]";
+
+// Shown in place of the subject of threads created without one
+const NO_SUBJECT: &str = "(no subject)";
+
+// Replies after which a thread accepts no more at all
+const MAX_REPLIES: i32 = 500;
+
+// Replies shown per page of a thread
+const REPLIES_PER_PAGE: i32 = 50;
+
+// Replies shown by the "Last 50" link of long threads
+const LAST_REPLIES: usize = 50;
+
+// Upper bound on the count accepted by /thread/{id}/last/{count}
+const MAX_LAST_REPLIES: usize = 1000;
+
+// Latest replies previewed under each thread on a board index
+const PREVIEW_REPLIES: usize = 3;
+
+// Maximum number of posts listed on a search results page
+const MAX_SEARCH_RESULTS: usize = 100;
+
+// Number of replies listed on the recent replies page
+const RECENT_REPLIES: usize = 50;
+
+// Number of open reports previewed on the admin dashboard
+const DASHBOARD_REPORTS: usize = 10;
+
+// Number of threads listed in the RSS feed
+const FEED_ITEMS: usize = 20;
+
+// Most URLs a single sitemap file may list, beyond this /sitemap.xml becomes a sitemap index
+const SITEMAP_MAX_URLS: usize = 50_000;
+
+// Characters of the opening post shown in link previews
+const OG_DESCRIPTION_LENGTH: usize = 200;
+
+// Address and port the server listens on unless BIND_ADDR and PORT say otherwise
+const DEFAULT_BIND_ADDR: std::net::IpAddr = std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED);
+const DEFAULT_PORT: u16 = 8080;
+
+// Response header echoing the request ID so it can be matched with the logs
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Seconds in-flight requests such as uploads get to finish after a shutdown signal
+const SHUTDOWN_TIMEOUT_SECS: u64 = 60;
+
+// Threads kept per board unless MAX_THREADS says otherwise
+const DEFAULT_MAX_THREADS: i32 = 200;
+
+// Open threads one client may have per board unless MAX_THREADS_PER_IP says otherwise
+const DEFAULT_MAX_THREADS_PER_IP: i32 = 10;
+
+// Longest side of image and video thumbnails in pixels unless THUMB_MAX_DIM says otherwise
+const DEFAULT_THUMB_MAX_DIM: i32 = 200;
+
+// How often boards are checked against the thread cap
+const PRUNE_INTERVAL: Duration = Duration::from_secs(600);
+
+// How often threads are checked for inactivity when AUTO_LOCK_DAYS is set
+const AUTO_LOCK_INTERVAL: Duration = Duration::from_secs(3600);
+
+// Minimum time a client must wait between posts
+const POST_COOLDOWN: Duration = Duration::from_secs(15);
+
+// Deletion password checks a client may make within DELETE_ATTEMPT_WINDOW
+const MAX_DELETE_ATTEMPTS: u32 = 5;
+
+// How long a client's deletion password checks are counted
+const DELETE_ATTEMPT_WINDOW: Duration = Duration::from_secs(600);
+
+// How often expired entries are swept out of the rate limiter
+const RATE_LIMIT_PRUNE_INTERVAL: Duration = Duration::from_secs(300);
+
+// How long the same message from the same client counts as a duplicate
+const DUPLICATE_CONTENT_WINDOW: Duration = Duration::from_secs(60);
+
+// Most recent messages remembered for duplicate detection at once
+const MAX_RECENT_CONTENT: usize = 10_000;
+
+// New replies buffered per watched thread before a slow listener starts missing some
+const THREAD_EVENT_BUFFER: usize = 16;
+
+// How often an idle event stream sends a comment, so closed connections are noticed
+const EVENT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+// Longest report reason accepted, in characters
+const MAX_REPORT_REASON_CHARS: usize = 200;
+
+// How long a CAPTCHA challenge can be answered
+const CAPTCHA_TTL: Duration = Duration::from_secs(300);
+
+// Most open CAPTCHA challenges kept in memory at once
+const MAX_PENDING_CAPTCHAS: usize = 10_000;
+
+// Name of the cookie holding a visitor's CAPTCHA token
+const CAPTCHA_COOKIE: &str = "captcha_token";
+
+// Name of the cookie that keeps an admin logged in
+const ADMIN_COOKIE: &str = "admin_session";
+
+// Longest poster name kept, in characters
+const MAX_NAME_CHARS: usize = 50;
+
+// Longest post deletion password accepted, in characters
+const MAX_DELETE_PASSWORD_CHARS: usize = 128;
+
+// Longest thread title and post message accepted, in characters (matching the forms' maxlength)
+const MAX_TITLE_CHARS: usize = 75;
+const MAX_MESSAGE_CHARS: usize = 8000;
+
+// Hex digits shown of a poster ID
+const POSTER_ID_LENGTH: usize = 8;
+
+// Sled key of the secret mixed into poster IDs
+const POSTER_ID_SECRET_KEY: &str = "poster_id_secret";
+
+// Sled key of the flag that stops new posts, set from /admin or with READ_ONLY
+const READ_ONLY_KEY: &str = "read_only";
+
+// Shown instead of the post forms and to posts refused while the board is read-only
+const READ_ONLY_NOTICE: &str = "The board is read-only for maintenance. No new posts can be made right now.";
+
+// Name shown on posts made without one
+const DEFAULT_NAME: &str = "Anonymous";
+
+// The main board lives at / and keeps its Sled keys unprefixed
+const MAIN_BOARD: &str = "";
+
+// Boards hosted alongside the main board as (slug, name). Slugs become part of
+// Sled keys and URLs, so only slugs on this list are ever accepted.
+const BOARDS: &[(&str, &str)] = &[("tech", "Technology"), ("random", "Random")];
+
+// Image formats the server can validate, strip and thumbnail
+const SUPPORTED_IMAGE_FORMATS: &[&str] = &["jpeg", "png", "gif", "webp", "bmp"];
+
+// Image formats accepted unless ALLOWED_IMAGE_FORMATS says otherwise
+const DEFAULT_IMAGE_FORMATS: &[&str] = &["jpeg", "png", "gif", "webp"];
+
+// Site name shown in page headers unless BOARD_TITLE says otherwise
+const DEFAULT_BOARD_TITLE: &str = "Rust Simple Imageboard 4";
+
+// Pages linked on each side of the current page before the rest collapse into an ellipsis
+const PAGINATION_WINDOW: i32 = 2;
+
+// Threads shown per page unless BOARD_PAGE_SIZE says otherwise
+const DEFAULT_PAGE_SIZE: i32 = 10;
+
+// Define the last post time of each client IP, shared by all workers
+type PostTimes = Arc<Mutex<HashMap<String, Instant>>>;
+
+// Define how many deletion passwords each client IP has tried and when its window started
+type DeleteAttempts = Arc<Mutex<HashMap<String, (u32, Instant)>>>;
+
+// Define when each client IP last posted each message, keyed by (IP, content hash)
+type RecentContent = Arc<Mutex<HashMap<(String, String), Instant>>>;
+
+// Define the optional GeoIP database used to look up poster countries
+type GeoIp = Option<Arc<maxminddb::Reader<Vec<u8>>>>;
+
+// Define the live update channels of watched threads, keyed by (board, thread ID)
+type ThreadEvents = Arc<Mutex<HashMap<(String, i32), broadcast::Sender<String>>>>;
+
+// Define the counters exposed on /metrics, kept since the server started
+#[derive(Default)]
+struct Metrics {
+    http_requests: AtomicU64,
+    threads_created: AtomicU64,
+    replies_created: AtomicU64,
+    image_uploads: AtomicU64,
+    video_uploads: AtomicU64,
+    audio_uploads: AtomicU64,
 }
 
-// Helper function to escape HTML content to prevent XSS
-fn escape_html(input: &str) -> String {
-    encode_safe(input).to_string()
+impl Metrics {
+    // Count the media attached to a new post
+    fn record_upload(&self, media_type: &Option<MediaType>) {
+        let counter = match media_type {
+            Some(MediaType::Image) => &self.image_uploads,
+            Some(MediaType::Video) => &self.video_uploads,
+            Some(MediaType::Audio) => &self.audio_uploads,
+            None => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
-// Helper function to render user-friendly error pages
-fn render_error_page(title: &str, message: &str) -> String {
-    format!(
-        r#"<!DOCTYPE html>
-<html lang="en">
-<head>
-    <meta charset="UTF-8">
-    <title>Error - {}</title>
-    <link rel="stylesheet" href="/static/style.css">
-</head>
-<body>
-    <div class="error-container">
-        <h1>{}</h1>
-        <p>{}</p>
-        <a href="/">Back to Home</a>
-    </div>
-</body>
-</html>"#,
-        escape_html(title),
-        escape_html(title),
-        escape_html(message)
-    )
+// Count of stored records skipped because they could not be read or decoded, reported by /metrics.
+// Kept outside Metrics since the database helpers that skip them are called from everywhere.
+static SKIPPED_RECORDS: AtomicU64 = AtomicU64::new(0);
+
+// Define a CAPTCHA challenge waiting to be answered
+struct Captcha {
+    answer: i32,
+    expires_at: Instant,
 }
 
-// Handler for the homepage displaying all threads with pagination
-async fn homepage(
-    db: web::Data<Arc<Db>>,
-    query: web::Query<PaginationParams>,
-) -> impl Responder {
-    let page_size = 10;
-    let page_number = query.page.unwrap_or(1).max(1);
+// Define the open CAPTCHA challenges keyed by the token in each visitor's cookie
+type Captchas = Arc<Mutex<HashMap<String, Captcha>>>;
+
+// Define the context of the error page template
+#[derive(Template)]
+#[template(path = "error.html")]
+struct ErrorTemplate<'a> {
+    title: &'a str,
+    message: &'a str,
+    request_id: Option<&'a str>, // Shown on server errors
+}
 
-    let mut threads = get_all_threads(&db);
-    threads.sort_by(|a, b| b.last_updated.cmp(&a.last_updated));
+// Define the context of the board index template
+#[derive(Template)]
+#[template(path = "board_index.html")]
+struct BoardIndexTemplate<'a> {
+    page_title: &'a str,
+    logo_html: &'a str, // Pre-rendered, already escaped
+    board: &'a str,
+    board_url: &'a str,
+    page_number: i32,
+    sort_new: bool, // Threads are listed by creation instead of bump order
+    uploads_enabled: bool, // Shows the file input and spoiler option
+    upload_accept: &'a str, // File extensions offered by the file input
+    captcha_question: &'a str,
+    read_only_notice: Option<&'a str>, // Shown instead of the thread form while the board is read-only
+    thread_list_html: &'a str, // Pre-rendered, already escaped
+    pagination_html: &'a str, // Pre-rendered, already escaped
+}
 
-    let total_threads = threads.len() as i32;
-    let total_pages = (total_threads as f64 / page_size as f64).ceil() as i32;
+// Define the context of the thread view template
+#[derive(Template)]
+#[template(path = "thread.html")]
+struct ThreadTemplate<'a> {
+    title: &'a str,
+    board_url: &'a str,
+    board_name: &'a str,
+    board: &'a str,
+    thread_id: i32,
+    captcha_question: Option<&'a str>, // None hides the reply form of a locked or full thread
+    closed_notice: &'a str, // Shown instead of the reply form
+    uploads_enabled: bool, // Shows the file input and spoiler option
+    upload_accept: &'a str, // File extensions offered by the file input
+    media_html: &'a str, // Pre-rendered, already escaped
+    poster_html: &'a str, // Pre-rendered, already escaped
+    timestamp: &'a str,
+    message_html: &'a str, // Pre-rendered, already escaped
+    report_form_html: &'a str, // Pre-rendered, already escaped
+    delete_form_html: &'a str, // Pre-rendered, already escaped
+    replies_html: &'a str, // Pre-rendered, already escaped
+    events_url: Option<&'a str>, // Live updates, only set when the newest replies are shown
+    pagination_html: &'a str, // Pre-rendered, already escaped
+    og_html: &'a str, // Pre-rendered, already escaped
+    logo_html: &'a str, // Pre-rendered, already escaped
+    message_prefill: &'a str, // Initial text of the reply form, e.g. a quote from ?quote=
+}
 
-    let page_number = if page_number > total_pages && total_pages > 0 {
-        total_pages
-    } else {
-        page_number
-    };
+// Define the fields of the admin login form
+#[derive(Deserialize)]
+struct LoginForm {
+    password: String,
+}
+
+// Define the state shared by every worker's application
+#[derive(Clone)]
+struct AppState {
+    db: Arc<Db>,
+    config: BoardConfig,
+    paths: DataPaths,
+    post_times: PostTimes,
+    delete_attempts: DeleteAttempts,
+    recent_content: RecentContent,
+    captchas: Captchas,
+    thread_events: ThreadEvents,
+    geoip: GeoIp,
+    metrics: Arc<Metrics>,
+}
+
+// Define runtime configuration read from the environment at startup
+#[derive(Clone)]
+struct BoardConfig {
+    page_size: i32, // Threads shown per page on the homepage
+    max_threads: i32, // Threads kept per board before the oldest are pruned
+    auto_lock_days: i32, // Days without a bump before a thread is locked, 0 never locks
+    max_threads_per_ip: i32, // Open threads one client may have per board, 0 for no limit
+    admin_password: Option<String>, // Admin routes are disabled when unset
+    admin_session: Option<String>, // Value of the admin cookie, changes on every restart
+    uploads_disabled: bool, // Text-only mode, the forms hide the file input and uploads are rejected
+    thumb_max_dim: u32, // Longest side of generated thumbnails in pixels
+    image_formats: Vec<String>, // Image subtypes accepted for upload, e.g. "jpeg"
+    title: String, // Name of the site shown in page headers
+    banner: Option<String>, // URL of an image shown above the title
+    robots_disallow_all: bool, // robots.txt asks crawlers to stay away entirely, for private instances
+    trusted_proxies: Vec<std::net::IpAddr>, // Reverse proxies whose X-Forwarded-For header is believed
+}
+
+impl BoardConfig {
+    fn from_env() -> Self {
+        let admin_password = std::env::var("ADMIN_PASSWORD").ok().filter(|password| !password.is_empty());
+        if admin_password.is_none() {
+            warn!("ADMIN_PASSWORD is not set, admin routes are disabled");
+        }
+        let admin_session = admin_password.as_deref().map(admin_session_token);
+
+        BoardConfig {
+            page_size: read_env_i32("BOARD_PAGE_SIZE", DEFAULT_PAGE_SIZE, 1, 100),
+            max_threads: read_env_i32("MAX_THREADS", DEFAULT_MAX_THREADS, 1, 100_000),
+            auto_lock_days: read_env_i32("AUTO_LOCK_DAYS", 0, 0, 3650),
+            max_threads_per_ip: read_env_i32("MAX_THREADS_PER_IP", DEFAULT_MAX_THREADS_PER_IP, 0, 1000),
+            admin_password,
+            admin_session,
+            uploads_disabled: read_env_flag("DISABLE_UPLOADS"),
+            thumb_max_dim: read_env_i32("THUMB_MAX_DIM", DEFAULT_THUMB_MAX_DIM, 50, 1000) as u32,
+            image_formats: read_image_formats(),
+            title: read_env_string("BOARD_TITLE").unwrap_or_else(|| DEFAULT_BOARD_TITLE.to_string()),
+            banner: read_env_string("BOARD_BANNER"),
+            robots_disallow_all: read_env_flag("ROBOTS_DISALLOW_ALL"),
+            trusted_proxies: read_trusted_proxies(),
+        }
+    }
+}
+
+// Define where the database and media files live, all beneath DATA_DIR.
+// Media directories end with a slash so file names can be appended directly.
+#[derive(Clone)]
+struct DataPaths {
+    sled_db: String,
+    image_uploads: String,
+    video_uploads: String,
+    audio_uploads: String,
+    image_thumbs: String,
+}
+
+impl DataPaths {
+    fn from_env() -> Self {
+        let data_dir = std::env::var("DATA_DIR")
+            .ok()
+            .map(|dir| dir.trim().trim_end_matches('/').to_string())
+            .filter(|dir| !dir.is_empty())
+            .unwrap_or_else(|| DEFAULT_DATA_DIR.to_string());
+        Self::in_dir(&data_dir)
+    }
+
+    // Every path beneath the given data directory, which has no trailing slash
+    fn in_dir(data_dir: &str) -> Self {
+        DataPaths {
+            sled_db: format!("{}/sled_db", data_dir),
+            image_uploads: format!("{}/uploads/images/", data_dir),
+            video_uploads: format!("{}/uploads/videos/", data_dir),
+            audio_uploads: format!("{}/uploads/audio/", data_dir),
+            image_thumbs: format!("{}/thumbs/images/", data_dir),
+        }
+    }
+
+    // Every directory media files are written to
+    fn media_dirs(&self) -> [&str; 4] {
+        [&self.image_uploads, &self.video_uploads, &self.audio_uploads, &self.image_thumbs]
+    }
+}
+
+// Define the ID assigned to each request, stored in its extensions
+#[derive(Clone)]
+struct RequestId(String);
+
+tokio::task_local! {
+    // ID of the request whose handler is running, read by the log format and error pages
+    static REQUEST_ID: String;
+}
+
+// Define proof that a request was made by a logged-in admin.
+// Handlers taking this extractor answer everyone else with a 401 page.
+struct Admin;
+
+impl FromRequest for Admin {
+    type Error = Error;
+    type Future = future::Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if is_admin(req) {
+            future::ok(Admin)
+        } else {
+            future::err(BoardError::Unauthorized("Log in at /admin/login to continue.".to_string()).into())
+        }
+    }
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    // Initialize the logger, tagging lines logged while handling a request with its ID
+    env_logger::Builder::from_default_env()
+        .format(|buf, record| {
+            let request_id = REQUEST_ID.try_with(|id| format!(" request_id={}", id)).unwrap_or_default();
+            writeln!(buf, "[{} {} {}] {}{}", buf.timestamp(), record.level(), record.target(), record.args(), request_id)
+        })
+        .init();
+
+    // Ensure the uploads and thumbnails directories exist under DATA_DIR
+    let paths = DataPaths::from_env();
+    for dir in paths.media_dirs() {
+        if !std::path::Path::new(dir).exists() {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                exit_on_startup_error(format!(
+                    "Failed to create directory {}: {}. Check that DATA_DIR points to a writable location.",
+                    dir, e
+                ));
+            }
+            info!("Created directory: {}", dir);
+        }
+    }
+
+    // Initialize the Sled database
+    let sled_db = match sled::open(&paths.sled_db) {
+        Ok(db) => Arc::new(db),
+        Err(e) => exit_on_startup_error(format!(
+            "Failed to open the database at {}: {}. Check its permissions and that no other instance is using it.",
+            paths.sled_db, e
+        )),
+    };
+
+    // Read the board configuration from the environment
+    let config = BoardConfig::from_env();
+
+    // READ_ONLY freezes the board from startup; it stays frozen until reopened from /admin
+    if read_env_flag("READ_ONLY") {
+        if let Err(e) = set_read_only(&sled_db, true) {
+            exit_on_startup_error(format!("Failed to make the board read-only: {}", e));
+        }
+        warn!("READ_ONLY is set, new posts are refused until posting is reopened from /admin");
+    }
+
+    // Track recent posters and deletion attempts in memory and periodically forget the expired ones
+    let post_times: PostTimes = Arc::new(Mutex::new(HashMap::new()));
+    let delete_attempts: DeleteAttempts = Arc::new(Mutex::new(HashMap::new()));
+    let (prune_times, prune_attempts) = (post_times.clone(), delete_attempts.clone());
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(RATE_LIMIT_PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            prune_post_times(&prune_times);
+            prune_delete_attempts(&prune_attempts);
+        }
+    });
+
+    // Keep CAPTCHA challenges in memory and periodically drop the expired ones
+    let captchas: Captchas = Arc::new(Mutex::new(HashMap::new()));
+    let prune_captchas = captchas.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(CAPTCHA_TTL);
+        loop {
+            interval.tick().await;
+            prune_expired_captchas(&prune_captchas);
+        }
+    });
+
+    // Remember recently posted messages to catch repeated spam, forgetting them after a minute
+    let recent_content: RecentContent = Arc::new(Mutex::new(HashMap::new()));
+    let prune_content = recent_content.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(DUPLICATE_CONTENT_WINDOW);
+        loop {
+            interval.tick().await;
+            prune_recent_content(&prune_content);
+        }
+    });
+
+    // Hand new replies to the pages watching their thread, dropping channels nobody listens to
+    let thread_events: ThreadEvents = Arc::new(Mutex::new(HashMap::new()));
+    let prune_events = thread_events.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(RATE_LIMIT_PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            prune_thread_events(&prune_events);
+        }
+    });
+
+    // Count requests and posts for /metrics
+    let metrics = Arc::new(Metrics::default());
+
+    // Periodically delete the oldest threads once a board grows past MAX_THREADS
+    let prune_db = sled_db.clone();
+    let prune_paths = paths.clone();
+    let max_threads = config.max_threads as usize;
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let db = prune_db.clone();
+            let paths = prune_paths.clone();
+            if let Err(e) = web::block(move || prune_threads(&db, &paths, max_threads)).await {
+                error!("Thread pruning failed: {}", e);
+            }
+        }
+    });
+
+    // Periodically lock threads nobody has bumped for AUTO_LOCK_DAYS, if set
+    if config.auto_lock_days > 0 {
+        let lock_db = sled_db.clone();
+        let max_age_days = config.auto_lock_days;
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(AUTO_LOCK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let db = lock_db.clone();
+                match web::block(move || lock_inactive_threads(&db, max_age_days)).await {
+                    Ok(locked) => info!("Locked {} threads inactive for {} days", locked, max_age_days),
+                    Err(e) => error!("Locking inactive threads failed: {}", e),
+                }
+            }
+        });
+    }
+
+    // Work out where and how to listen before starting anything that serves requests
+    let bind_address = read_bind_address().unwrap_or_else(|e| exit_on_startup_error(e.to_string()));
+    let tls_config = load_tls_config().unwrap_or_else(|e| exit_on_startup_error(e.to_string()));
+
+    // Posts only get a country when a GeoIP database is configured and readable
+    let geoip = load_geoip();
+
+    // Start the Actix-web server
+    let flush_db = sled_db.clone();
+    let state = AppState {
+        db: sled_db,
+        config,
+        paths,
+        post_times,
+        delete_attempts,
+        recent_content,
+        captchas,
+        thread_events,
+        geoip,
+        metrics,
+    };
+    let server = HttpServer::new(move || build_app(state.clone()));
+
+    // Serve HTTPS directly when a certificate was configured, plain HTTP otherwise
+    let scheme = if tls_config.is_some() { "https" } else { "http" };
+    let server = match tls_config {
+        Some(tls_config) => {
+            info!("TLS enabled, serving HTTPS");
+            server.bind_rustls_0_23(bind_address, tls_config)
+        }
+        None => {
+            info!("TLS_CERT and TLS_KEY are not set, serving plain HTTP");
+            server.bind(bind_address)
+        }
+    };
+    let server = server.unwrap_or_else(|e| {
+        exit_on_startup_error(format!(
+            "Failed to listen on {}: {}. Check that the port is free and BIND_ADDR is an address of this machine.",
+            bind_address, e
+        ))
+    });
+
+    // Log the addresses actually bound, which differ from the requested one for PORT=0
+    for address in server.addrs() {
+        info!("Listening on {}://{}", scheme, address);
+    }
+
+    server.shutdown_timeout(SHUTDOWN_TIMEOUT_SECS).run().await?;
+
+    // Actix handles SIGINT/SIGTERM itself: it stops accepting connections and lets in-flight
+    // requests finish before run() returns, so everything they wrote can be flushed here
+    info!("Server stopped, flushing database");
+    match flush_db.flush_async().await {
+        Ok(bytes) => info!("Flushed {} bytes to sled db", bytes),
+        Err(e) => error!("Failed to flush sled db on shutdown: {}", e),
+    }
+    Ok(())
+}
+
+// Function to build the application with its routes and middleware. Every worker builds its own
+// from the shared state, and tests build one against a temporary database.
+fn build_app(
+    state: AppState,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = Error,
+        InitError = (),
+    >,
+> {
+    let paths = state.paths.clone();
+    App::new()
+        .app_data(web::Data::new(state.db))
+        .app_data(web::Data::new(state.config))
+        .app_data(web::Data::new(state.paths))
+        .app_data(web::Data::new(state.post_times))
+        .app_data(web::Data::new(state.delete_attempts))
+        .app_data(web::Data::new(state.recent_content))
+        .app_data(web::Data::new(state.captchas))
+        .app_data(web::Data::new(state.thread_events))
+        .app_data(web::Data::new(state.geoip))
+        .app_data(web::Data::new(state.metrics))
+        .app_data(web::PayloadConfig::new(MAX_REQUEST_BYTES))
+        .app_data(web::FormConfig::default().limit(MAX_REQUEST_BYTES))
+        .wrap_fn(|req, srv| {
+            if let Some(metrics) = req.app_data::<web::Data<Arc<Metrics>>>() {
+                metrics.http_requests.fetch_add(1, Ordering::Relaxed);
+            }
+
+            // Reject oversized uploads from their Content-Length before reading the body
+            if exceeds_request_limit(&req) {
+                let response = BoardError::TooLarge("The upload is too large.".to_string()).error_response();
+                return Either::Left(future::ok(req.into_response(response)));
+            }
+            Either::Right(srv.call(req))
+        })
+        // Stop browsers from sniffing uploads into HTML and lock pages down to this origin
+        .wrap(
+            middleware::DefaultHeaders::new()
+                .add((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+                .add((header::CONTENT_SECURITY_POLICY, CONTENT_SECURITY_POLICY)),
+        )
+        .wrap(
+            middleware::Logger::new(r#"%{client_ip}xi "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T request_id=%{request_id}xi"#)
+                .custom_request_replace("client_ip", |req| client_ip(req.request()))
+                .custom_request_replace("request_id", |req| {
+                    req.extensions().get::<RequestId>().map(|id| id.0.clone()).unwrap_or_default()
+                }),
+        )
+        // Give every request an ID for the logs, error pages and the X-Request-Id header.
+        // Registered last so it runs first and the Logger can already see the ID.
+        .wrap_fn(|req, srv| {
+            let request_id = Uuid::new_v4().to_string();
+            req.extensions_mut().insert(RequestId(request_id.clone()));
+            let response = REQUEST_ID.scope(request_id.clone(), srv.call(req));
+            async move {
+                let mut response = response.await?;
+                if let Ok(value) = header::HeaderValue::from_str(&request_id) {
+                    response.headers_mut().insert(header::HeaderName::from_static(REQUEST_ID_HEADER), value);
+                }
+                Ok(response)
+            }
+        })
+        // Uploaded media is already compressed, so it is served outside the Compress scope.
+        // Files are named by UUID and never rewritten, so browsers may cache them for good.
+        .service(
+            web::scope("/uploads")
+                .wrap(media_headers())
+                .service(media_files("/images", &paths.image_uploads)) // Serve uploaded images
+                .service(media_files("/videos", &paths.video_uploads)) // Serve uploaded videos
+                .service(media_files("/audio", &paths.audio_uploads)), // Serve uploaded audio
+        )
+        .service(
+            web::scope("/thumbs")
+                .wrap(media_headers())
+                .service(media_files("/images", &paths.image_thumbs)), // Serve image thumbnails
+        )
+        .service(
+            web::scope("")
+                .wrap(middleware::Compress::default())
+                .service(fs::Files::new("/static", "./static")) // Disabled directory listing
+                .route("/healthz", web::get().to(healthz))
+                .route("/readyz", web::get().to(readyz))
+                .route("/metrics", web::get().to(metrics_endpoint))
+                .route("/", web::get().to(homepage))
+                .route("/catalog", web::get().to(catalog))
+                .route("/search", web::get().to(search))
+                .route("/recent", web::get().to(recent_replies))
+                .route("/feed.xml", web::get().to(rss_feed))
+                .route("/robots.txt", web::get().to(robots_txt))
+                .route("/sitemap.xml", web::get().to(sitemap))
+                .route("/sitemap-{page:\\d+}.xml", web::get().to(sitemap_page))
+                .route("/thread/{id}", web::get().to(view_thread))
+                .route("/thread/{id}/last/{count}", web::get().to(view_thread_last))
+                .route("/thread/{id}/events", web::get().to(thread_events_stream))
+                .route("/thread/{id}/feed.xml", web::get().to(thread_feed))
+                .route("/thread", web::post().to(create_thread))
+                .route("/reply", web::post().to(create_reply))
+                .route("/api/threads", web::get().to(api_threads))
+                .route("/api/thread", web::post().to(api_create_thread))
+                .route("/api/captcha", web::get().to(api_captcha))
+                .route("/api/thread/{id}", web::get().to(api_thread))
+                .route("/api/thread/{id}/reply/{reply_id}", web::get().to(api_reply))
+                .route("/api/{board}/threads", web::get().to(board_api_threads))
+                .route("/api/{board}/thread/{id}", web::get().to(board_api_thread))
+                .route("/api/{board}/thread/{id}/reply/{reply_id}", web::get().to(board_api_reply))
+                .route("/thread/{id}/delete", web::post().to(delete_thread))
+                .route("/thread/{id}/sticky", web::post().to(toggle_sticky))
+                .route("/thread/{id}/lock", web::post().to(toggle_lock))
+                .route("/reply/{parent_id}/{reply_id}/delete", web::post().to(delete_reply))
+                .route("/admin", web::get().to(admin_dashboard))
+                .route("/admin/purge", web::post().to(purge_deleted))
+                .route("/admin/restore", web::post().to(restore_post))
+                .route("/admin/read-only", web::post().to(toggle_read_only))
+                .route("/admin/login", web::get().to(admin_login_page))
+                .route("/admin/login", web::post().to(admin_login))
+                .route("/admin/logout", web::post().to(admin_logout))
+                .route("/admin/bans", web::get().to(admin_bans))
+                .route("/admin/ban", web::post().to(ban_ip))
+                .route("/admin/unban", web::post().to(unban_ip))
+                .route("/admin/export", web::get().to(admin_export))
+                .route("/admin/records", web::get().to(admin_unreadable_records))
+                .service(
+                    web::resource("/admin/import")
+                        .app_data(web::JsonConfig::default().limit(MAX_REQUEST_BYTES))
+                        .route(web::post().to(admin_import)),
+                )
+                .route("/admin/reports", web::get().to(admin_reports))
+                .route("/admin/reports/dismiss", web::post().to(dismiss_report))
+                .route("/admin/reports/delete", web::post().to(delete_reported_post))
+                .route("/report", web::post().to(report_post))
+                .route("/delete", web::post().to(delete_own_post))
+                .route("/boards", web::get().to(board_list))
+                .route("/{board}/", web::get().to(board_index))
+                .route("/{board}/catalog", web::get().to(board_catalog))
+                .route("/{board}/search", web::get().to(board_search))
+                .route("/{board}/feed.xml", web::get().to(board_rss_feed))
+                .route("/{board}/thread/{id}", web::get().to(board_thread))
+                .route("/{board}/thread/{id}/last/{count}", web::get().to(board_thread_last))
+                .route("/{board}/thread/{id}/events", web::get().to(board_thread_events_stream))
+                .route("/{board}/thread/{id}/feed.xml", web::get().to(board_thread_feed))
+                .route("/{board}/thread/{id}/delete", web::post().to(board_delete_thread))
+                .route("/{board}/thread/{id}/sticky", web::post().to(board_toggle_sticky))
+                .route("/{board}/thread/{id}/lock", web::post().to(board_toggle_lock))
+                .route("/{board}/reply/{parent_id}/{reply_id}/delete", web::post().to(board_delete_reply)),
+        )
+        .default_service(web::to(not_found)) // Friendly 404 page for unknown routes
+}
+
+// Helper function to stop the server during startup with a readable log line and a nonzero
+// exit code, instead of a panic backtrace or the debug form of an io::Error
+fn exit_on_startup_error(message: String) -> ! {
+    error!("{}", message);
+    std::process::exit(1);
+}
+
+// Helper function to read the listening address from BIND_ADDR and PORT.
+// Unlike the board settings, a bad address stops startup rather than binding somewhere unexpected.
+fn read_bind_address() -> std::io::Result<std::net::SocketAddr> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+
+    let ip = match std::env::var("BIND_ADDR") {
+        Ok(value) => value
+            .trim()
+            .parse::<std::net::IpAddr>()
+            .map_err(|_| invalid(format!("BIND_ADDR must be an IP address such as 127.0.0.1, got {:?}", value)))?,
+        Err(_) => DEFAULT_BIND_ADDR,
+    };
+
+    let port = match std::env::var("PORT") {
+        Ok(value) => value
+            .trim()
+            .parse::<u16>()
+            .map_err(|_| invalid(format!("PORT must be a number from 0 to 65535, got {:?}", value)))?,
+        Err(_) => DEFAULT_PORT,
+    };
+
+    Ok(std::net::SocketAddr::new(ip, port))
+}
+
+// Helper function to load the TLS certificate chain and private key named by TLS_CERT and TLS_KEY.
+// Returns None when neither is set; a half-configured or unreadable pair stops startup.
+fn load_tls_config() -> std::io::Result<Option<rustls::ServerConfig>> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+
+    let (cert_path, key_path) = match (std::env::var("TLS_CERT").ok(), std::env::var("TLS_KEY").ok()) {
+        (None, None) => return Ok(None),
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        _ => return Err(invalid("TLS_CERT and TLS_KEY must be set together".to_string())),
+    };
+
+    let certs = CertificateDer::pem_file_iter(&cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(format!("Failed to read TLS certificate from {}: {}", cert_path, e)))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("No certificates found in TLS_CERT file {}", cert_path)));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(&key_path)
+        .map_err(|e| invalid(format!("Failed to read TLS private key from {}: {}", key_path, e)))?;
+
+    rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map(Some)
+        .map_err(|e| invalid(format!("TLS_CERT and TLS_KEY do not form a usable certificate: {}", e)))
+}
+
+// Helper function to open the MaxMind country database named by GEOIP_DB.
+// A missing or unreadable file only disables country lookups, since they are cosmetic.
+fn load_geoip() -> GeoIp {
+    let path = std::env::var("GEOIP_DB").ok().filter(|path| !path.trim().is_empty())?;
+    match maxminddb::Reader::open_readfile(&path) {
+        Ok(reader) => {
+            info!("Loaded GeoIP database {}", path);
+            Some(Arc::new(reader))
+        }
+        Err(e) => {
+            warn!("Failed to load GeoIP database {}, posts will have no country: {}", path, e);
+            None
+        }
+    }
+}
+
+// Helper function to look up the two-letter country code of a client IP.
+// Private and loopback addresses, and addresses the database does not know, have none.
+fn lookup_country(geoip: &GeoIp, ip: &str) -> Option<String> {
+    let reader = geoip.as_ref()?;
+    let ip: std::net::IpAddr = ip.parse().ok()?;
+    let is_private = match ip {
+        std::net::IpAddr::V4(v4) => v4.is_private() || v4.is_loopback() || v4.is_link_local(),
+        std::net::IpAddr::V6(v6) => v6.is_loopback() || (v6.segments()[0] & 0xfe00) == 0xfc00,
+    };
+    if is_private {
+        return None;
+    }
+
+    let country: maxminddb::geoip2::Country = reader.lookup(ip).ok()?;
+    let code = country.country?.iso_code?;
+    (code.len() == 2 && code.bytes().all(|byte| byte.is_ascii_alphabetic())).then(|| code.to_ascii_uppercase())
+}
+
+// Helper function to build the headers of uploaded media: long caching and a sandboxing policy
+// that takes the place of the page policy set further out
+fn media_headers() -> middleware::DefaultHeaders {
+    middleware::DefaultHeaders::new()
+        .add((header::CACHE_CONTROL, MEDIA_CACHE_CONTROL))
+        .add((header::CONTENT_SECURITY_POLICY, MEDIA_CONTENT_SECURITY_POLICY))
+}
+
+// Helper function to serve a media folder. Files get the content type of their extension, which
+// the server chose, and only images, video and audio are shown inline; anything else is downloaded.
+fn media_files(mount_path: &str, dir: &str) -> fs::Files {
+    fs::Files::new(mount_path, dir).mime_override(|mime_type| match *mime_type {
+        mime::IMAGE | mime::VIDEO | mime::AUDIO => header::DispositionType::Inline,
+        _ => header::DispositionType::Attachment,
+    })
+}
+
+// Helper function to read the ALLOWED_IMAGE_FORMATS list, e.g. "jpeg,png".
+// Formats the server cannot handle are skipped with a warning, and an empty list keeps the defaults.
+fn read_image_formats() -> Vec<String> {
+    let value = match std::env::var("ALLOWED_IMAGE_FORMATS") {
+        Ok(value) => value,
+        Err(_) => return DEFAULT_IMAGE_FORMATS.iter().map(|format| format.to_string()).collect(),
+    };
+
+    let mut formats: Vec<String> = Vec::new();
+    for format in value.split(',').map(|format| format.trim().to_ascii_lowercase()).filter(|format| !format.is_empty()) {
+        let format = if format == "jpg" { "jpeg".to_string() } else { format };
+        if !SUPPORTED_IMAGE_FORMATS.contains(&format.as_str()) {
+            warn!("ALLOWED_IMAGE_FORMATS lists unsupported format {}, ignoring it", format);
+        } else if !formats.contains(&format) {
+            formats.push(format);
+        }
+    }
+    if formats.is_empty() {
+        warn!("ALLOWED_IMAGE_FORMATS names no supported format, using the defaults");
+        return DEFAULT_IMAGE_FORMATS.iter().map(|format| format.to_string()).collect();
+    }
+    formats
+}
+
+// Helper function to read the comma-separated addresses of the reverse proxies in front of the board
+fn read_trusted_proxies() -> Vec<std::net::IpAddr> {
+    let value = read_env_string("TRUSTED_PROXIES").unwrap_or_default();
+    let mut proxies = Vec::new();
+    for proxy in value.split(',').map(str::trim).filter(|proxy| !proxy.is_empty()) {
+        match proxy.parse() {
+            Ok(ip) => proxies.push(ip),
+            Err(_) => warn!("TRUSTED_PROXIES lists invalid IP address {}, ignoring it", proxy),
+        }
+    }
+    proxies
+}
+
+// Helper function to list the file extensions the upload forms offer, from the allowed image formats
+fn upload_accept(config: &BoardConfig) -> String {
+    config
+        .image_formats
+        .iter()
+        .map(|format| if format == "jpeg" { ".jpg,.jpeg".to_string() } else { format!(".{}", format) })
+        .chain(std::iter::once(".mp4,.webm,.mp3,.ogg".to_string()))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+// Helper function to read a text setting from the environment, treating a blank value as unset
+fn read_env_string(name: &str) -> Option<String> {
+    std::env::var(name).ok().map(|value| value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+// Helper function to read a bounded integer setting from the environment.
+// Invalid values log a warning and fall back to the default instead of panicking.
+fn read_env_i32(name: &str, default: i32, min: i32, max: i32) -> i32 {
+    let value = match std::env::var(name) {
+        Ok(value) => value,
+        Err(_) => return default,
+    };
+
+    match value.trim().parse::<i32>() {
+        Ok(parsed) if (min..=max).contains(&parsed) => parsed,
+        Ok(parsed) => {
+            warn!("{} must be between {} and {}, clamping {}", name, min, max, parsed);
+            parsed.clamp(min, max)
+        }
+        Err(_) => {
+            warn!("Invalid {} value {:?}, using default {}", name, value, default);
+            default
+        }
+    }
+}
+
+// Helper function to read an on/off setting from the environment, off unless set to 1, true or yes
+fn read_env_flag(name: &str) -> bool {
+    match std::env::var(name) {
+        Ok(value) => matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes"),
+        Err(_) => false,
+    }
+}
+
+// Helper function to check a request's declared body size against MAX_REQUEST_BYTES
+fn exceeds_request_limit(req: &ServiceRequest) -> bool {
+    req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok())
+        .is_some_and(|length| length > MAX_REQUEST_BYTES)
+}
+
+// Helper function to tidy a message before it is stored: line endings become \n, trailing
+// whitespace is cut from every line and runs of blank lines collapse into one
+fn normalize_message(message: &str) -> String {
+    let mut normalized = String::with_capacity(message.len());
+    let mut newlines = 0;
+    for line in message.trim().lines() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            newlines += 1;
+            continue;
+        }
+        if !normalized.is_empty() {
+            normalized.push_str(if newlines > 0 { "\n\n" } else { "\n" });
+        }
+        normalized.push_str(line);
+        newlines = 0;
+    }
+    normalized
+}
+
+// Helper function to pick the subject shown for a thread, which may have been created without one
+fn display_subject(title: &str) -> &str {
+    if title.is_empty() {
+        NO_SUBJECT
+    } else {
+        title
+    }
+}
+
+// Helper function to escape HTML content to prevent XSS
+fn escape_html(input: &str) -> String {
+    encode_safe(input).to_string()
+}
+
+// Helper function to escape text for use inside a double-quoted HTML attribute
+fn escape_attribute(input: &str) -> String {
+    html_escape::encode_double_quoted_attribute(input).to_string()
+}
+
+// Helper function to escape text for XML documents such as the RSS feed
+fn escape_xml(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            '\'' => output.push_str("&apos;"),
+            // Control characters other than whitespace are not allowed in XML 1.0
+            c if c.is_control() && !matches!(c, '\n' | '\r' | '\t') => {}
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+// Helper function to render a post message as safe HTML
+fn render_message(message: &str, thread_url: &str) -> String {
+    link_post_references(&render_greentext(&escape_html(message)), thread_url)
+}
+
+// Helper function to colour quoted lines starting with > in green.
+// Lines starting with >> are post references and are left alone.
+fn render_greentext(escaped: &str) -> String {
+    escaped
+        .split('\n')
+        .map(|line| line.trim_end_matches('\r'))
+        .map(|line| {
+            if line.starts_with("&gt;") && !line.starts_with("&gt;&gt;") {
+                format!(r#"<span class="greentext">{}</span>"#, line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("<br>")
+}
+
+// Helper function to turn >>N post references into links to replies in the thread.
+// It works on already-escaped text, so it can't be used to inject markup.
+fn link_post_references(escaped: &str, thread_url: &str) -> String {
+    const QUOTE: &str = "&gt;&gt;";
+
+    let mut output = String::with_capacity(escaped.len());
+    let mut rest = escaped;
+    while let Some(position) = rest.find(QUOTE) {
+        output.push_str(&rest[..position]);
+        let after = &rest[position + QUOTE.len()..];
+        let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+
+        if digits > 0 {
+            output.push_str(&format!(
+                r#"<a href="{}#reply-{}" class="quote-link">&gt;&gt;{}</a>"#,
+                thread_url,
+                &after[..digits],
+                &after[..digits]
+            ));
+        } else {
+            output.push_str(QUOTE);
+        }
+        rest = &after[digits..];
+    }
+    output.push_str(rest);
+
+    output
+}
+
+// Helper function to split a "name#secret" name field into the display name and tripcode.
+// Only the hash of the secret is kept, so the secret itself is never stored.
+fn parse_name_and_tripcode(input: &str) -> (Option<String>, Option<String>) {
+    let (name, secret) = match input.split_once('#') {
+        Some((name, secret)) => (name.trim(), Some(secret)),
+        None => (input.trim(), None),
+    };
+
+    let name: String = name.chars().take(MAX_NAME_CHARS).collect();
+    let name = (!name.trim_end().is_empty()).then(|| name.trim_end().to_string());
+    let tripcode = secret
+        .filter(|secret| !secret.is_empty())
+        .map(|secret| {
+            let digest = format!("{:x}", Sha256::digest(format!("tripcode:{}", secret).as_bytes()));
+            format!("!{}", &digest[..10])
+        });
+    (name, tripcode)
+}
+
+// Helper function to render the poster's name and tripcode in a post header
+fn render_poster(
+    name: &Option<String>,
+    tripcode: &Option<String>,
+    poster_id: &Option<String>,
+    country: &Option<String>,
+) -> String {
+    let name = name.as_deref().filter(|name| !name.is_empty()).unwrap_or(DEFAULT_NAME);
+    let mut html = String::new();
+    if let Some(country) = country {
+        html.push_str(&format!(
+            r#"<span class="country" title="{}">{}</span>"#,
+            escape_html(country),
+            country_flag(country)
+        ));
+    }
+    html.push_str(&format!(r#"<span class="name">{}</span>"#, escape_html(name)));
+    if let Some(tripcode) = tripcode {
+        html.push_str(&format!(r#"<span class="tripcode">{}</span>"#, escape_html(tripcode)));
+    }
+    if let Some(poster_id) = poster_id {
+        html.push_str(&format!(
+            r#"<span class="poster-id" style="background-color: hsl({}, 60%, 75%)">ID: {}</span>"#,
+            poster_id_hue(poster_id),
+            escape_html(poster_id)
+        ));
+    }
+    html
+}
+
+// Helper function to turn a two-letter country code into its flag emoji (a pair of regional indicators)
+fn country_flag(country: &str) -> String {
+    if country.len() != 2 || !country.bytes().all(|byte| byte.is_ascii_alphabetic()) {
+        return escape_html(country);
+    }
+    country
+        .to_ascii_uppercase()
+        .bytes()
+        .filter_map(|letter| char::from_u32(0x1F1E6 + (letter - b'A') as u32))
+        .collect()
+}
+
+// Helper function to derive the color of a poster ID so the same ID is always the same color
+fn poster_id_hue(poster_id: &str) -> u32 {
+    poster_id.bytes().fold(0u32, |hash, byte| hash.wrapping_mul(31).wrapping_add(byte as u32)) % 360
+}
+
+// Helper function to compute a poster's ID in a thread: a hash of their IP, the thread and
+// the current UTC day, so posts by one visitor match within a thread but not across threads or days.
+// Returns None if the salt cannot be read, leaving the post without an ID.
+fn compute_poster_id(db: &Db, ip: &str, board: &str, thread_id: i32) -> Option<String> {
+    let secret = poster_id_secret(db)?;
+    let day = Utc::now().format("%Y-%m-%d");
+    let digest = Sha256::digest(format!("poster_id:{}:{}:{}:{}:{}", secret, day, ip, board, thread_id).as_bytes());
+    Some(format!("{:x}", digest)[..POSTER_ID_LENGTH].to_string())
+}
+
+// Helper function to hash a thread creator's IP with the poster ID secret, so threads can be
+// counted per client without storing the address itself
+fn compute_creator_hash(db: &Db, ip: &str) -> Option<String> {
+    let secret = poster_id_secret(db)?;
+    Some(format!("{:x}", Sha256::digest(format!("creator:{}:{}", secret, ip).as_bytes())))
+}
+
+// Helper function to reject a new thread from a client that already has MAX_THREADS_PER_IP
+// threads open on the board. Deleted threads no longer count.
+fn check_thread_limit(db: &Db, config: &BoardConfig, board: &str, creator_hash: Option<&str>) -> Result<(), BoardError> {
+    let creator_hash = match creator_hash {
+        Some(creator_hash) if config.max_threads_per_ip > 0 => creator_hash,
+        _ => return Ok(()),
+    };
+    let open_threads = get_all_threads_for_board(db, board)
+        .iter()
+        .filter(|thread| !thread.deleted && thread.creator_hash.as_deref() == Some(creator_hash))
+        .count();
+    if open_threads < config.max_threads_per_ip as usize {
+        return Ok(());
+    }
+
+    Err(BoardError::RateLimited {
+        title: "Too Many Threads",
+        message: format!(
+            "You have reached the limit of {} open threads on this board. Reply to one of them instead.",
+            config.max_threads_per_ip
+        ),
+        retry_after: None,
+    })
+}
+
+// Function to read the random secret behind poster IDs, creating it on first use.
+// It is kept in Sled so IDs stay the same across restarts within a day.
+fn poster_id_secret(db: &Db) -> Option<String> {
+    let new_secret = Uuid::new_v4().to_string();
+    // Only the first writer wins, so concurrent first posts agree on the secret
+    if let Err(e) = db.compare_and_swap(POSTER_ID_SECRET_KEY, None as Option<&[u8]>, Some(new_secret.as_bytes())) {
+        error!("Failed to store the poster ID secret: {}", e);
+        return None;
+    }
+    match db.get(POSTER_ID_SECRET_KEY) {
+        Ok(Some(secret)) => Some(String::from_utf8_lossy(&secret).into_owned()),
+        Ok(None) => None,
+        Err(e) => {
+            error!("Failed to read the poster ID secret: {}", e);
+            None
+        }
+    }
+}
+
+// Helper function to format a Unix timestamp for display
+fn format_timestamp(timestamp: i64) -> String {
+    if timestamp <= 0 {
+        return "unknown".to_string();
+    }
+
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// Helper function to reject a trimmed text field longer than max characters.
+// Counts Unicode scalar values like the browser's maxlength, so multibyte text isn't penalized.
+fn check_text_length(field: &str, text: &str, max: usize) -> Result<(), BoardError> {
+    if text.trim().chars().count() > max {
+        return Err(BoardError::BadRequest(format!("{} cannot be longer than {} characters", field, max)));
+    }
+    Ok(())
+}
+
+// Helper function to derive the admin cookie value from the password and a per-process secret,
+// so the password itself never leaves the server and old cookies stop working after a restart
+fn admin_session_token(password: &str) -> String {
+    let secret = Uuid::new_v4();
+    format!("{:x}", Sha256::digest(format!("admin_session:{}:{}", secret, password).as_bytes()))
+}
+
+// Helper function to check a request's Bearer password or admin cookie
+fn is_admin(req: &HttpRequest) -> bool {
+    let config = match req.app_data::<web::Data<BoardConfig>>() {
+        Some(config) => config,
+        None => return false,
+    };
+    let (password, session) = match (&config.admin_password, &config.admin_session) {
+        (Some(password), Some(session)) => (password, session),
+        _ => return false,
+    };
+
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if bearer.is_some_and(|given| constant_time_eq(given.as_bytes(), password.as_bytes())) {
+        return true;
+    }
+
+    req.cookie(ADMIN_COOKIE)
+        .is_some_and(|cookie| constant_time_eq(cookie.value().as_bytes(), session.as_bytes()))
+}
+
+// Helper function to compare secrets without exiting early on the first differing byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Helper function to get the client IP. This is the connecting address unless that is one of the
+// TRUSTED_PROXIES, in which case X-Forwarded-For is read from the right, past any further trusted
+// proxies, since everything to the left of what our own proxies appended is up to the client.
+fn client_ip(req: &HttpRequest) -> String {
+    let mut ip = match req.peer_addr() {
+        Some(addr) => addr.ip(),
+        None => return "unknown".to_string(),
+    };
+    let trusted_proxies = req
+        .app_data::<web::Data<BoardConfig>>()
+        .map(|config| config.trusted_proxies.as_slice())
+        .unwrap_or_default();
+    let forwarded: Vec<&str> = req
+        .headers()
+        .get_all("X-Forwarded-For")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    for hop in forwarded.iter().rev() {
+        if !trusted_proxies.contains(&ip) {
+            break;
+        }
+        match hop.parse() {
+            Ok(hop) => ip = hop,
+            Err(_) => break,
+        }
+    }
+    ip.to_string()
+}
+
+// Helper function to reject a post made before the client's cooldown has passed
+fn check_post_cooldown(post_times: &PostTimes, ip: &str) -> Result<(), BoardError> {
+    let times = post_times.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let elapsed = match times.get(ip) {
+        Some(last_post) => last_post.elapsed(),
+        None => return Ok(()),
+    };
+    if elapsed >= POST_COOLDOWN {
+        return Ok(());
+    }
+
+    let wait = (POST_COOLDOWN - elapsed).as_secs() + 1;
+    Err(BoardError::RateLimited {
+        title: "Please Wait",
+        message: format!("Please wait {} more seconds before posting again.", wait),
+        retry_after: Some(wait),
+    })
+}
+
+// Helper function to reject posts from a client with an active ban
+fn check_not_banned(db: &Db, ip: &str) -> Result<(), BoardError> {
+    let ban = match active_ban(db, ip) {
+        Some(ban) => ban,
+        None => return Ok(()),
+    };
+
+    let mut message = format!("You are banned from posting until {}.", format_timestamp(ban.expires_at));
+    if !ban.reason.is_empty() {
+        message.push_str(&format!(" Reason: {}", ban.reason));
+    }
+    Err(BoardError::Forbidden("You Are Banned", message))
+}
+
+// Function to check whether the board is read-only. A flag that cannot be read
+// leaves posting open rather than freezing the board over a storage hiccup.
+fn is_read_only(db: &Db) -> bool {
+    match db.get(READ_ONLY_KEY) {
+        Ok(flag) => flag.is_some(),
+        Err(e) => {
+            error!("Failed to read the read-only flag: {}", e);
+            false
+        }
+    }
+}
+
+// Function to turn read-only mode on or off, kept in Sled so it survives restarts
+fn set_read_only(db: &Db, read_only: bool) -> sled::Result<()> {
+    if read_only {
+        db.insert(READ_ONLY_KEY, &[1u8][..])?;
+    } else {
+        db.remove(READ_ONLY_KEY)?;
+    }
+    Ok(())
+}
+
+// Helper function to reject posts while the board is read-only
+fn check_not_read_only(db: &Db) -> Result<(), BoardError> {
+    if !is_read_only(db) {
+        return Ok(());
+    }
+    Err(BoardError::Unavailable("Board Is Read-Only", READ_ONLY_NOTICE.to_string()))
+}
+
+// Helper function to open a CAPTCHA challenge, returning its token and question.
+// The operands come from a random UUID so no extra RNG dependency is needed.
+fn new_captcha(captchas: &Captchas) -> (String, String) {
+    let token = Uuid::new_v4();
+    let bytes = token.as_bytes();
+    let (a, b) = (i32::from(bytes[0] % 10) + 1, i32::from(bytes[1] % 10) + 1);
+
+    let mut pending = captchas.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if pending.len() >= MAX_PENDING_CAPTCHAS {
+        let now = Instant::now();
+        pending.retain(|_, captcha| captcha.expires_at > now);
+        if pending.len() >= MAX_PENDING_CAPTCHAS {
+            warn!("Too many open CAPTCHA challenges, dropping all of them");
+            pending.clear();
+        }
+    }
+    pending.insert(
+        token.to_string(),
+        Captcha {
+            answer: a + b,
+            expires_at: Instant::now() + CAPTCHA_TTL,
+        },
+    );
+
+    (token.to_string(), format!("What is {} + {}?", a, b))
+}
+
+// Helper function to build the cookie tying a visitor to their CAPTCHA challenge
+fn captcha_cookie(token: String) -> Cookie<'static> {
+    Cookie::build(CAPTCHA_COOKIE, token)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(actix_web::cookie::time::Duration::seconds(CAPTCHA_TTL.as_secs() as i64))
+        .finish()
+}
+
+// Helper function to check a CAPTCHA answer. The challenge is removed on the
+// first attempt so a token can never be reused.
+fn verify_captcha(captchas: &Captchas, req: &HttpRequest, answer: &str) -> bool {
+    let token = match req.cookie(CAPTCHA_COOKIE) {
+        Some(cookie) => cookie.value().to_string(),
+        None => return false,
+    };
+    let captcha = {
+        let mut pending = captchas.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        pending.remove(&token)
+    };
+
+    match captcha {
+        Some(captcha) => captcha.expires_at > Instant::now() && answer.trim().parse() == Ok(captcha.answer),
+        None => false,
+    }
+}
+
+// Helper function to build the error returned for a wrong or expired CAPTCHA answer
+fn captcha_failed_error() -> BoardError {
+    BoardError::BadRequest("Incorrect or expired CAPTCHA answer. Reload the page to get a new question and try again.".to_string())
+}
+
+// Helper function to drop CAPTCHA challenges that can no longer be answered
+fn prune_expired_captchas(captchas: &Captchas) {
+    let now = Instant::now();
+    let mut pending = captchas.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    pending.retain(|_, captcha| captcha.expires_at > now);
+}
+
+// Helper function to start a client's cooldown after a successful post
+fn record_post_time(post_times: &PostTimes, ip: String) {
+    let mut times = post_times.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    times.insert(ip, Instant::now());
+}
+
+// Helper function to hash a post's trimmed message for duplicate detection
+fn hash_content(message: &str) -> String {
+    format!("{:x}", Sha256::digest(message.trim().as_bytes()))
+}
+
+// Helper function to reject a message the client already posted within the duplicate window
+fn check_not_duplicate(recent_content: &RecentContent, ip: &str, content_hash: &str) -> Result<(), BoardError> {
+    let recent = recent_content.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    match recent.get(&(ip.to_string(), content_hash.to_string())) {
+        Some(posted_at) if posted_at.elapsed() < DUPLICATE_CONTENT_WINDOW => Err(BoardError::BadRequest(
+            "Duplicate post detected. You already posted this message a moment ago.".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Helper function to remember a message a client just posted.
+// The map is capped so a flood of distinct messages cannot grow it without bound.
+fn record_recent_content(recent_content: &RecentContent, ip: &str, content_hash: String) {
+    let mut recent = recent_content.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if recent.len() >= MAX_RECENT_CONTENT {
+        recent.retain(|_, posted_at| posted_at.elapsed() < DUPLICATE_CONTENT_WINDOW);
+        if recent.len() >= MAX_RECENT_CONTENT {
+            warn!("Too many recent posts tracked for duplicate detection, forgetting all of them");
+            recent.clear();
+        }
+    }
+    recent.insert((ip.to_string(), content_hash), Instant::now());
+}
+
+// Helper function to forget messages posted longer ago than the duplicate window
+fn prune_recent_content(recent_content: &RecentContent) {
+    let mut recent = recent_content.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    recent.retain(|_, posted_at| posted_at.elapsed() < DUPLICATE_CONTENT_WINDOW);
+}
+
+// Helper function to drop clients whose cooldown has passed so the map stays small
+fn prune_post_times(post_times: &PostTimes) {
+    let mut times = post_times.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    times.retain(|_, last_post| last_post.elapsed() < POST_COOLDOWN);
+}
+
+// Helper function to count a deletion password check against the client, refusing it once the
+// client has used up MAX_DELETE_ATTEMPTS in the current window. Every check counts, not only
+// failed ones, so a burst of parallel requests cannot all get through before the first one fails.
+fn take_delete_attempt(delete_attempts: &DeleteAttempts, ip: &str) -> Result<(), BoardError> {
+    let mut attempts = delete_attempts.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let (count, window_start) = attempts.entry(ip.to_string()).or_insert((0, Instant::now()));
+    if window_start.elapsed() >= DELETE_ATTEMPT_WINDOW {
+        *count = 0;
+        *window_start = Instant::now();
+    }
+    if *count >= MAX_DELETE_ATTEMPTS {
+        let wait = (DELETE_ATTEMPT_WINDOW - window_start.elapsed()).as_secs() + 1;
+        return Err(BoardError::RateLimited {
+            title: "Too Many Attempts",
+            message: format!("Too many deletion attempts. Please wait {} more seconds.", wait),
+            retry_after: Some(wait),
+        });
+    }
+    *count += 1;
+    Ok(())
+}
+
+// Helper function to drop clients whose deletion attempt window has passed
+fn prune_delete_attempts(delete_attempts: &DeleteAttempts) {
+    let mut attempts = delete_attempts.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    attempts.retain(|_, (_, window_start)| window_start.elapsed() < DELETE_ATTEMPT_WINDOW);
+}
+
+// Helper function to start listening for a thread's new replies, opening its channel on first use
+fn subscribe_thread_events(thread_events: &ThreadEvents, board: &str, thread_id: i32) -> broadcast::Receiver<String> {
+    let mut channels = thread_events.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    channels
+        .entry((board.to_string(), thread_id))
+        .or_insert_with(|| broadcast::channel(THREAD_EVENT_BUFFER).0)
+        .subscribe()
+}
+
+// Helper function to push an event to a thread's listeners, closing the channel once they have all left
+fn publish_thread_event(thread_events: &ThreadEvents, board: &str, thread_id: i32, event: String) {
+    let mut channels = thread_events.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let key = (board.to_string(), thread_id);
+    if let Some(sender) = channels.get(&key) {
+        if sender.send(event).is_err() {
+            channels.remove(&key);
+        }
+    }
+}
+
+// Helper function to drop the channels of threads nobody is watching anymore
+fn prune_thread_events(thread_events: &ThreadEvents) {
+    let mut channels = thread_events.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    channels.retain(|_, sender| sender.receiver_count() > 0);
+}
+
+// Helper function to format a server-sent event, prefixing every line of its payload
+fn format_sse_event(event: &str, data: &str) -> String {
+    let mut message = format!("event: {}\n", event);
+    for line in data.lines() {
+        message.push_str("data: ");
+        message.push_str(line);
+        message.push('\n');
+    }
+    message.push('\n');
+    message
+}
+
+// Helper function to render user-friendly error pages
+fn render_error_page(title: &str, message: &str) -> String {
+    ErrorTemplate { title, message, request_id: None }
+        .render()
+        .expect("Failed to render error page")
+}
+
+// Helper function to render the error page for failures on our side.
+// It shows the request ID so users can quote it when reporting the problem.
+fn render_server_error_page(message: &str) -> String {
+    let request_id = REQUEST_ID.try_with(|id| id.clone()).ok();
+    ErrorTemplate { title: "Internal Server Error", message, request_id: request_id.as_deref() }
+        .render()
+        .expect("Failed to render error page")
+}
+
+// Handler for the homepage displaying all threads with pagination
+async fn homepage(
+    db: web::Data<Arc<Db>>,
+    config: web::Data<BoardConfig>,
+    captchas: web::Data<Captchas>,
+    query: web::Query<BoardIndexParams>,
+) -> impl Responder {
+    let sort = ThreadSort::from_query(query.sort.as_deref());
+    render_board_index(&db, &config, &captchas, MAIN_BOARD, query.page, sort)
+}
+
+// Handler for the index page of one of the extra boards
+async fn board_index(
+    db: web::Data<Arc<Db>>,
+    config: web::Data<BoardConfig>,
+    captchas: web::Data<Captchas>,
+    path: web::Path<String>,
+    query: web::Query<BoardIndexParams>,
+) -> impl Responder {
+    let sort = ThreadSort::from_query(query.sort.as_deref());
+    match validate_board(&path.into_inner()) {
+        Some(board) if board != MAIN_BOARD => Ok(render_board_index(&db, &config, &captchas, board, query.page, sort)),
+        _ => Err(board_not_found_error()),
+    }
+}
+
+// Helper function to render a board's thread list with pagination
+fn render_board_index(
+    db: &Db,
+    config: &BoardConfig,
+    captchas: &Captchas,
+    board: &str,
+    requested_page: Option<i32>,
+    sort: ThreadSort,
+) -> HttpResponse {
+    let page_size = config.page_size;
+
+    let mut threads = get_all_threads_for_board(db, board);
+    match sort {
+        ThreadSort::Bump => sort_threads_by_bump(&mut threads),
+        ThreadSort::New => sort_threads_by_creation(&mut threads),
+    }
+
+    let (page_number, total_pages, range) = paginate(threads.len(), requested_page, page_size);
+    let threads = &threads[range];
+    let (token, question) = new_captcha(captchas);
+
+    // Generate HTML for the list of threads
+    let thread_list_html = if threads.is_empty() {
+        "<p>No threads found. Be the first to create one!</p>".to_string()
+    } else {
+        // One key scan per rendered thread gives the count, only the previewed replies are decoded
+        threads
+            .iter()
+            .map(|thread| {
+                let (reply_count, latest_replies) = get_latest_replies(db, board, thread.id, PREVIEW_REPLIES);
+                format!(
+                    "{}{}",
+                    render_thread(board, thread, reply_count),
+                    render_reply_previews(board, thread, reply_count, &latest_replies)
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("<hr>")
+    };
+
+    // Generate HTML for pagination controls, keeping the chosen order across pages
+    let board_url = board_path(board);
+    let pagination_base = match sort {
+        ThreadSort::Bump => board_url.clone(),
+        ThreadSort::New => format!("{}?sort=new", board_url),
+    };
+    let pagination_html = render_pagination(&pagination_base, page_number, total_pages);
+
+    // Assemble the complete HTML for the homepage
+    let page_title = board_title(config, board);
+    let html = BoardIndexTemplate {
+        page_title: &page_title,
+        logo_html: &render_logo(config, &page_title),
+        board,
+        board_url: &board_url,
+        page_number,
+        sort_new: sort == ThreadSort::New,
+        uploads_enabled: !config.uploads_disabled,
+        upload_accept: &upload_accept(config),
+        captcha_question: &question,
+        read_only_notice: is_read_only(db).then_some(READ_ONLY_NOTICE),
+        thread_list_html: &thread_list_html,
+        pagination_html: &pagination_html,
+    }
+    .render()
+    .expect("Failed to render board index");
+
+    HttpResponse::Ok()
+        .cookie(captcha_cookie(token))
+        .content_type("text/html")
+        .body(html)
+}
+
+// Handler for the landing page listing every board
+async fn board_list(db: web::Data<Arc<Db>>, config: web::Data<BoardConfig>) -> impl Responder {
+    let boards_html = all_boards()
+        .map(|board| {
+            format!(
+                r#"<li><a href="{}">{}</a> (threads: {})</li>"#,
+                board_path(board),
+                escape_html(&board_title(&config, board)),
+                get_all_threads_for_board(&db, board).len()
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>Boards</title>
+    <link rel="stylesheet" href="/static/style.css">
+</head>
+<body>
+    {}
+    <hr>
+
+    <!-- Board List -->
+    <ul class="board-list">
+        {}
+    </ul>
+
+    <div class="footer">
+        - Powered by Rust and Actix Web -
+    </div>
+</body>
+</html>"#,
+        render_logo(&config, &config.title),
+        boards_html
+    );
+
+    HttpResponse::Ok().content_type("text/html").body(html)
+}
+
+// Helper function to build the error for boards that are not on the allow-list
+fn board_not_found_error() -> BoardError {
+    BoardError::NotFound("Board Not Found", "The requested board does not exist.".to_string())
+}
+
+// Helper function to build the error for threads that do not exist
+fn thread_not_found_error() -> BoardError {
+    BoardError::NotFound("Thread Not Found", "The requested thread does not exist.".to_string())
+}
+
+// Helper function to build the error for threads that have reached MAX_REPLIES
+fn thread_full_error() -> BoardError {
+    BoardError::Forbidden(
+        "Thread Full",
+        "This thread has reached its reply limit and no longer accepts replies.".to_string(),
+    )
+}
+
+// Handler for any URL that no route matches
+async fn not_found() -> Result<HttpResponse, BoardError> {
+    Err(BoardError::NotFound("Not Found", "The requested page does not exist.".to_string()))
+}
+
+// Helper function to work out which slice of a list belongs on a page.
+// Returns the clamped page number, the total page count and the index range to show.
+fn paginate(total_items: usize, requested_page: Option<i32>, page_size: i32) -> (i32, i32, std::ops::Range<usize>) {
+    let total_pages = (total_items as f64 / page_size as f64).ceil() as i32;
+    let page_number = requested_page.unwrap_or(1).clamp(1, total_pages.max(1));
+
+    let start_index = ((page_number - 1) * page_size) as usize;
+    let end_index = (start_index + page_size as usize).min(total_items);
+    (page_number, total_pages, start_index.min(end_index)..end_index)
+}
+
+// Function to choose the page links shown in pagination controls: the first and last pages and a
+// window around the current one, with gaps for the pages skipped in between. A gap would only
+// hide a single page, so that page is listed instead.
+fn pagination_items(page_number: i32, total_pages: i32) -> Vec<PageItem> {
+    let window_start = (page_number - PAGINATION_WINDOW).max(1);
+    let window_end = (page_number + PAGINATION_WINDOW).min(total_pages);
+    let mut pages: Vec<i32> = std::iter::once(1)
+        .chain(window_start..=window_end)
+        .chain(std::iter::once(total_pages))
+        .filter(|page| (1..=total_pages).contains(page))
+        .collect();
+    pages.sort_unstable();
+    pages.dedup();
+
+    let mut items = Vec::new();
+    let mut previous = 0;
+    for page in pages {
+        match page - previous {
+            1 => {}
+            2 => items.push(PageItem::Page(previous + 1)),
+            _ => items.push(PageItem::Gap),
+        }
+        items.push(PageItem::Page(page));
+        previous = page;
+    }
+    items
+}
+
+// Helper function to render pagination controls linking to base_path?page=N
+fn render_pagination(base_path: &str, page_number: i32, total_pages: i32) -> String {
+    let mut pagination_html = String::new();
+
+    // The base path may already carry a query string such as ?sort=new
+    let separator = if base_path.contains('?') { "&amp;" } else { "?" };
+
+    pagination_html.push_str(r#"<div class="pagination">"#);
+
+    if page_number > 1 {
+        pagination_html.push_str(&format!(
+            r#"<a href="{}{}page={}">Previous</a>"#,
+            base_path,
+            separator,
+            page_number - 1
+        ));
+    }
+
+    let items = pagination_items(page_number, total_pages);
+    for item in &items {
+        match *item {
+            PageItem::Page(page) if page == page_number => pagination_html.push_str(&format!(
+                r#"<span class="current">{}</span>"#,
+                page
+            )),
+            PageItem::Page(page) => pagination_html.push_str(&format!(
+                r#"<a href="{}{}page={}">{}</a>"#,
+                base_path, separator, page, page
+            )),
+            PageItem::Gap => pagination_html.push_str(r#"<span class="gap">…</span>"#),
+        }
+    }
+
+    if page_number < total_pages {
+        pagination_html.push_str(&format!(
+            r#"<a href="{}{}page={}">Next</a>"#,
+            base_path,
+            separator,
+            page_number + 1
+        ));
+    }
+
+    // Pages hidden behind an ellipsis can still be reached by number, keeping the rest of the query
+    if items.contains(&PageItem::Gap) {
+        let (action, query) = base_path.split_once('?').unwrap_or((base_path, ""));
+        let hidden_fields: String = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(name, value)| {
+                format!(r#"<input type="hidden" name="{}" value="{}">"#, escape_attribute(name), escape_attribute(value))
+            })
+            .collect();
+        pagination_html.push_str(&format!(
+            r#"<form class="inline-form jump-to-page" action="{}" method="get">{}<input type="number" name="page" min="1" max="{}" placeholder="Page" aria-label="Go to page"><input type="submit" value="Go"></form>"#,
+            escape_attribute(action),
+            hidden_fields,
+            total_pages
+        ));
+    }
+
+    pagination_html.push_str(r#"</div>"#);
+
+    pagination_html
+}
+
+// Helper function to render individual threads
+fn render_thread(board: &str, thread: &Thread, reply_count: i32) -> String {
+    let thread_url = thread_path(board, thread.id);
+
+    // Deleted threads stay listed as a placeholder so their replies can still be reached
+    if thread.deleted {
+        return format!(
+            r#"<div class="post thread-post deleted-post">
+    <div class="post-content">
+        <div class="post-header">
+            <span class="title">{}</span>
+            <span class="timestamp">{}</span>
+            <a href="{}" class="reply-link">View</a>
+            <span class="reply-count">{}</span>
+        </div>
+    </div>
+</div>"#,
+            DELETED_PLACEHOLDER,
+            format_timestamp(thread.created_at),
+            thread_url,
+            format_reply_count(reply_count)
+        );
+    }
+
+    // Videos are previewed by their thumbnail on the board and played in the thread view
+    let media_html = match (&thread.media_type, &thread.thumb_url) {
+        (Some(MediaType::Video), Some(thumb_url)) => format!(
+            r#"<div class="post-media">
+    <a href="{}"><img src="{}" alt="Video Thumbnail" class="video-thumb"></a>
+</div>"#,
+            thread_url,
+            escape_html(thumb_url)
+        ),
+        // Spoilered images are replaced by a generic thumbnail until the thread is opened
+        (Some(MediaType::Image), _) if thread.spoiler => format!(
+            r#"<div class="post-media">
+    <a href="{}"><img src="{}" alt="Spoiler Image" class="spoiler-thumb"></a>
+</div>"#,
+            thread_url,
+            SPOILER_THUMB
+        ),
+        // Animated GIFs and WEBPs are previewed by a still of their first frame
+        (Some(MediaType::Image), Some(thumb_url)) => format!(
+            r#"<div class="post-media">
+    <a href="{}"><img src="{}" alt="Animated Image Thumbnail" class="gif-thumb"></a>
+</div>"#,
+            thread_url,
+            escape_html(thumb_url)
+        ),
+        _ => render_media(&thread.media_url, &thread.media_type, false, "Thread Image"),
+    };
+    let media_html = format!("{}{}", media_html, render_media_info(&thread.media_info));
+
+    format!(
+        r#"<div class="post thread-post">
+    {}
+    <div class="post-content">
+        <div class="post-header">
+            {}{}<span class="title">{}</span>
+            {}
+            <span class="timestamp">{}</span>
+            <a href="{}" class="reply-link">Reply</a>
+            {}
+            <span class="reply-count">{}</span>
+            {}
+        </div>
+        <div class="message">{}</div>
+    </div>
+</div>"#,
+        media_html,
+        if thread.sticky {
+            r#"<span class="sticky" title="Sticky">📌</span>"#
+        } else {
+            ""
+        },
+        if thread.locked {
+            r#"<span class="locked" title="Locked">🔒</span>"#
+        } else {
+            ""
+        },
+        escape_html(display_subject(&thread.title)),
+        render_poster(&thread.name, &thread.tripcode, &thread.poster_id, &thread.country),
+        format_timestamp(thread.created_at),
+        thread_url,
+        if reply_count as usize > LAST_REPLIES {
+            format!(r#"<a href="{}/last/{}" class="reply-link">Last {}</a>"#, thread_url, LAST_REPLIES, LAST_REPLIES)
+        } else {
+            String::new()
+        },
+        format_reply_count(reply_count),
+        if reply_count >= MAX_REPLIES {
+            r#"<span class="bump-limit">Thread full</span>"#
+        } else if reply_count >= BUMP_LIMIT {
+            r#"<span class="bump-limit">Bump limit reached</span>"#
+        } else {
+            ""
+        },
+        render_message(&thread.message, &thread_url)
+    )
+}
+
+// Helper function to render a post's attached image or video
+fn render_media(media_url: &Option<String>, media_type: &Option<MediaType>, spoiler: bool, alt: &str) -> String {
+    if let (Some(url), Some(media_type)) = (media_url, media_type) {
+        match media_type {
+            MediaType::Image => format!(
+                r#"<div class="post-media">
+    <img src="{}" alt="{}" class="toggle-image{}">
+</div>"#,
+                escape_html(url),
+                escape_html(alt),
+                if spoiler { " spoiler" } else { "" }
+            ),
+            MediaType::Video => format!(
+                r#"<div class="post-media">
+    <video controls class="video-player">
+        <source src="{}" type="{}">
+        Your browser does not support the video tag.
+    </video>
+</div>"#,
+                escape_html(url),
+                mime_guess::from_path(url).first_raw().unwrap_or("video/mp4")
+            ),
+            MediaType::Audio => format!(
+                r#"<div class="post-media">
+    <audio controls class="audio-player">
+        <source src="{}" type="{}">
+        Your browser does not support the audio tag.
+    </audio>
+</div>"#,
+                escape_html(url),
+                mime_guess::from_path(url).first_raw().unwrap_or("audio/mpeg")
+            ),
+        }
+    } else {
+        "".to_string()
+    }
+}
+
+// Helper function to render an image's dimensions and size, e.g. "1920x1080, 2.3 MB"
+fn render_media_info(media_info: &Option<MediaInfo>) -> String {
+    match media_info {
+        Some(info) => format!(
+            r#"<div class="media-info">{}x{}, {}</div>"#,
+            info.width,
+            info.height,
+            format_file_size(info.file_size)
+        ),
+        None => String::new(),
+    }
+}
+
+// Helper function to format a byte count for display
+fn format_file_size(bytes: u64) -> String {
+    const KB: u64 = 1024;
+    const MB: u64 = 1024 * KB;
+    if bytes >= MB {
+        format!("{:.1} MB", bytes as f64 / MB as f64)
+    } else if bytes >= KB {
+        format!("{:.0} KB", bytes as f64 / KB as f64)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+// Helper function to describe how many replies a thread has
+fn format_reply_count(reply_count: i32) -> String {
+    if reply_count == 1 {
+        "1 reply".to_string()
+    } else {
+        format!("{} replies", reply_count)
+    }
+}
+
+// Helper function to order threads as a board lists them: stickies first, then most recently bumped.
+// Threads bumped in the same second fall back to the newest ID, so pages never overlap or skip one.
+fn sort_threads_by_bump(threads: &mut [Thread]) {
+    threads.sort_by_key(|thread| {
+        (std::cmp::Reverse(thread.sticky), std::cmp::Reverse(thread.last_updated), std::cmp::Reverse(thread.id))
+    });
+}
+
+// Helper function to order threads newest-created first, with sticky threads still on top
+fn sort_threads_by_creation(threads: &mut [Thread]) {
+    threads.sort_by_key(|thread| {
+        (std::cmp::Reverse(thread.sticky), std::cmp::Reverse(thread.created_at), std::cmp::Reverse(thread.id))
+    });
+}
+
+// Function to apply a change to a stored thread and save it back.
+// Returns the updated thread, or None when the thread does not exist.
+fn update_thread(
+    db: &Db,
+    board: &str,
+    thread_id: i32,
+    update: impl FnOnce(&mut Thread),
+) -> sled::Result<Option<Thread>> {
+    let key = thread_key(board, thread_id);
+    let mut thread: Thread = match db.get(&key)?.and_then(|value| serde_json::from_slice(&value).ok()) {
+        Some(thread) => thread,
+        None => return Ok(None),
+    };
+
+    update(&mut thread);
+    let value = serde_json::to_vec(&thread).expect("Failed to serialize thread");
+    db.insert(key, value)?;
+    Ok(Some(thread))
+}
+
+// Function to fetch all threads of a board from the Sled database
+fn get_all_threads_for_board(db: &Db, board: &str) -> Vec<Thread> {
+    db.scan_prefix(format!("{}thread_", board_key_prefix(board)))
+        .filter_map(decode_scanned_record)
+        .collect()
+}
+
+// Helper function to decode one record of a Sled scan. Unreadable records are skipped so one bad
+// entry cannot take a page down, but they are logged with their key and counted for /metrics.
+fn decode_scanned_record<T: serde::de::DeserializeOwned>(res: sled::Result<(sled::IVec, sled::IVec)>) -> Option<T> {
+    let (key, value) = match res {
+        Ok(entry) => entry,
+        Err(e) => {
+            error!("Failed to read a record while scanning sled db: {}", e);
+            SKIPPED_RECORDS.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+    };
+    match serde_json::from_slice(&value) {
+        Ok(record) => Some(record),
+        Err(e) => {
+            error!("Skipping unreadable record {}: {}", String::from_utf8_lossy(&key), e);
+            SKIPPED_RECORDS.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+// Function to allocate the ID for a new thread on a board, or None once every ID has been used
+fn next_thread_id(db: &Db, board: &str) -> sled::Result<Option<i32>> {
+    next_id(db, &format!("{}next_thread_id", board_key_prefix(board)), i32::MAX, || {
+        get_all_threads_for_board(db, board).iter().map(|thread| thread.id).max().unwrap_or(0)
+    })
+}
+
+// Helper function to build the Sled key of a thread
+fn thread_key(board: &str, thread_id: i32) -> Vec<u8> {
+    format!("{}thread_{}", board_key_prefix(board), thread_id).into_bytes()
+}
+
+// Helper function to look up a board slug on the allow-list.
+// The empty slug is the main board.
+fn validate_board(slug: &str) -> Option<&'static str> {
+    if slug.is_empty() {
+        return Some(MAIN_BOARD);
+    }
+    BOARDS.iter().map(|(board, _)| *board).find(|board| *board == slug)
+}
+
+// Helper function to iterate over every board, starting with the main board
+fn all_boards() -> impl Iterator<Item = &'static str> {
+    std::iter::once(MAIN_BOARD).chain(BOARDS.iter().map(|(board, _)| *board))
+}
+
+// Helper function to build the Sled key prefix of a board (empty for the main board)
+fn board_key_prefix(board: &str) -> String {
+    if board.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", board)
+    }
+}
+
+// Helper function to build the URL of a board's index page
+fn board_path(board: &str) -> String {
+    if board.is_empty() {
+        "/".to_string()
+    } else {
+        format!("/{}/", board)
+    }
+}
+
+// Helper function to build the URL of a thread on a board
+fn thread_path(board: &str, thread_id: i32) -> String {
+    format!("{}thread/{}", board_path(board), thread_id)
+}
+
+// Helper function to build the API path of a thread on a board
+fn api_thread_path(board: &str, thread_id: i32) -> String {
+    format!("/api{}", thread_path(board, thread_id))
+}
+
+// Helper function to describe a board in page headers, the main board goes by the site title
+fn board_title(config: &BoardConfig, board: &str) -> String {
+    match BOARDS.iter().find(|(slug, _)| *slug == board) {
+        Some((slug, name)) => format!("/{}/ - {}", slug, name),
+        None => config.title.clone(),
+    }
+}
+
+// Helper function to render the page header, with the operator's banner image above the text if set
+fn render_logo(config: &BoardConfig, text: &str) -> String {
+    let banner_html = config
+        .banner
+        .as_ref()
+        .map(|banner| format!(r#"<img class="banner" src="{}" alt="{}"><br>"#, escape_attribute(banner), escape_attribute(text)))
+        .unwrap_or_default();
+    format!(r#"<div class="logo">{}{}</div>"#, banner_html, escape_html(text))
+}
+
+// Function to atomically take the next ID from a Sled counter key, or None once the counter has
+// reached the limit. Checking the limit in the same update lets concurrent callers never exceed it.
+// A missing counter is seeded from the highest ID already stored, so boards created
+// before the counter existed never hand out an ID that is in use. IDs are never reused.
+fn next_id(db: &Db, counter_key: &str, limit: i32, highest_existing: impl FnOnce() -> i32) -> sled::Result<Option<i32>> {
+    let seed = if db.contains_key(counter_key)? {
+        0
+    } else {
+        highest_existing()
+    };
+
+    let decode = |bytes: &[u8]| <[u8; 4]>::try_from(bytes).ok().map(i32::from_be_bytes);
+    let mut allocated = None;
+    db.update_and_fetch(counter_key, |old| {
+        // Sled may retry the update under contention, so only its final run counts
+        let current = old.and_then(decode).unwrap_or(seed);
+        allocated = (current < limit).then_some(current + 1);
+        Some(allocated.unwrap_or(current).to_be_bytes().to_vec())
+    })?;
+    Ok(allocated)
+}
+
+// Handler for the catalog displaying every thread of the main board as a thumbnail grid
+async fn catalog(db: web::Data<Arc<Db>>, config: web::Data<BoardConfig>) -> impl Responder {
+    render_catalog(&db, &config, MAIN_BOARD)
+}
+
+// Handler for the catalog of one of the extra boards
+async fn board_catalog(
+    db: web::Data<Arc<Db>>,
+    config: web::Data<BoardConfig>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, BoardError> {
+    match validate_board(&path.into_inner()) {
+        Some(board) if board != MAIN_BOARD => Ok(render_catalog(&db, &config, board)),
+        _ => Err(board_not_found_error()),
+    }
+}
+
+// Helper function to render a board's threads as a thumbnail grid
+fn render_catalog(db: &Db, config: &BoardConfig, board: &str) -> HttpResponse {
+    let mut threads = get_all_threads_for_board(db, board);
+    sort_threads_by_bump(&mut threads);
+    for thread in &mut threads {
+        thread.redact_if_deleted();
+    }
+
+    // Tally every thread's replies in one pass instead of scanning per thread
+    let reply_counts = count_all_replies(db, board);
+
+    let catalog_html = if threads.is_empty() {
+        "<p>No threads found. Be the first to create one!</p>".to_string()
+    } else {
+        threads
+            .iter()
+            .map(|thread| render_catalog_item(board, thread, reply_counts.get(&thread.id).copied().unwrap_or(0)))
+            .collect::<Vec<String>>()
+            .join("")
+    };
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>Catalog - {}</title>
+    <link rel="stylesheet" href="/static/style.css">
+</head>
+<body>
+    {}
+    <div class="board-nav"><a href="{}">Back to Board</a></div>
+    <hr>
+
+    <!-- Catalog Grid -->
+    <div class="catalog">
+        {}
+    </div>
+
+    <div class="footer">
+        - Powered by Rust and Actix Web -
+    </div>
+</body>
+</html>"#,
+        escape_html(&board_title(config, board)),
+        render_logo(config, &board_title(config, board)),
+        board_path(board),
+        catalog_html
+    );
+
+    HttpResponse::Ok().content_type("text/html").body(html)
+}
+
+// Helper function to render a single catalog tile
+fn render_catalog_item(board: &str, thread: &Thread, reply_count: i32) -> String {
+    let thumb_html = match (&thread.media_url, &thread.media_type) {
+        (Some(_), Some(MediaType::Image)) if thread.spoiler => format!(
+            r#"<img src="{}" alt="Spoiler Image" class="catalog-thumb">"#,
+            SPOILER_THUMB
+        ),
+        (Some(url), Some(MediaType::Image)) => format!(
+            r#"<img src="{}" alt="Thread Image" class="catalog-thumb">"#,
+            escape_html(thread.thumb_url.as_ref().unwrap_or(url))
+        ),
+        (Some(_), Some(MediaType::Video)) => match &thread.thumb_url {
+            Some(thumb_url) => format!(
+                r#"<img src="{}" alt="Video Thumbnail" class="catalog-thumb">"#,
+                escape_html(thumb_url)
+            ),
+            None => r#"<div class="catalog-thumb catalog-placeholder">Video</div>"#.to_string(),
+        },
+        (Some(_), Some(MediaType::Audio)) => r#"<div class="catalog-thumb catalog-placeholder">Audio</div>"#.to_string(),
+        _ => r#"<div class="catalog-thumb catalog-placeholder">No Media</div>"#.to_string(),
+    };
+
+    format!(
+        r#"<div class="catalog-item">
+    <a href="{}">{}</a>
+    <div class="catalog-title">{}</div>
+    <div class="reply-count">{}</div>
+</div>"#,
+        thread_path(board, thread.id),
+        thumb_html,
+        escape_html(&truncate_chars(display_subject(&thread.title), 40)),
+        format_reply_count(reply_count)
+    )
+}
+
+// Helper function to render the OpenGraph tags that chat apps use for link previews of a thread.
+// The image must be an absolute URL, so it is built from the host the page was requested on.
+fn render_og_tags(req: &HttpRequest, thread: &Thread) -> String {
+    let description = thread.message.split_whitespace().collect::<Vec<&str>>().join(" ");
+    let mut html = format!(
+        r#"<meta property="og:type" content="article">
+    <meta property="og:title" content="{}">
+    <meta property="og:description" content="{}">"#,
+        escape_attribute(display_subject(&thread.title)),
+        escape_attribute(&truncate_chars(&description, OG_DESCRIPTION_LENGTH))
+    );
+
+    // Spoilered images and audio have nothing safe to preview
+    let image_url = match thread.media_type {
+        _ if thread.spoiler => None,
+        Some(MediaType::Image) => thread.thumb_url.as_ref().or(thread.media_url.as_ref()),
+        Some(MediaType::Video) => thread.thumb_url.as_ref(),
+        _ => None,
+    };
+    if let Some(image_url) = image_url {
+        let connection = req.connection_info();
+        html.push_str(&format!(
+            r#"
+    <meta property="og:image" content="{}://{}{}">"#,
+            connection.scheme(),
+            escape_attribute(connection.host()),
+            escape_attribute(image_url)
+        ));
+    }
+    html
+}
+
+// Helper function to shorten text to a maximum number of characters
+fn truncate_chars(input: &str, max_chars: usize) -> String {
+    if input.chars().count() <= max_chars {
+        input.to_string()
+    } else {
+        let truncated: String = input.chars().take(max_chars).collect();
+        format!("{}…", truncated)
+    }
+}
+
+// Handler for full-text search across the main board's thread titles, messages and replies
+async fn search(
+    db: web::Data<Arc<Db>>,
+    config: web::Data<BoardConfig>,
+    query: web::Query<SearchParams>,
+) -> impl Responder {
+    render_search(&db, &config, MAIN_BOARD, query.q.as_deref().unwrap_or(""))
+}
+
+// Handler for searching one of the extra boards
+async fn board_search(
+    db: web::Data<Arc<Db>>,
+    config: web::Data<BoardConfig>,
+    path: web::Path<String>,
+    query: web::Query<SearchParams>,
+) -> Result<HttpResponse, BoardError> {
+    match validate_board(&path.into_inner()) {
+        Some(board) if board != MAIN_BOARD => Ok(render_search(&db, &config, board, query.q.as_deref().unwrap_or(""))),
+        _ => Err(board_not_found_error()),
+    }
+}
+
+// Helper function to render the search form and the posts on a board matching the query
+fn render_search(db: &Db, config: &BoardConfig, board: &str, query: &str) -> HttpResponse {
+    let search_query = query.trim().to_string();
+
+    let results_html = if search_query.is_empty() {
+        "<p>Enter a word or phrase to search the board.</p>".to_string()
+    } else {
+        // A linear scan is fine for a board of this size
+        let mut threads = get_all_threads_for_board(db, board);
+        threads.sort_by_key(|thread| std::cmp::Reverse(thread.last_updated));
+        let replies = get_all_replies(db, board);
+
+        // Deleted posts are kept as placeholders but never turn up in results
+        let posts = threads
+            .iter()
+            .filter(|thread| !thread.deleted)
+            .map(SearchPost::Thread)
+            .chain(replies.iter().filter(|(_, reply)| !reply.deleted).map(|(thread_id, reply)| SearchPost::Reply {
+                thread_id: *thread_id,
+                reply,
+            }));
+        let matches: Vec<String> = posts
+            .filter(|post| post_matches(&search_query, post))
+            .take(MAX_SEARCH_RESULTS)
+            .map(|post| render_search_result(board, &search_query, &post))
+            .collect();
+
+        if matches.is_empty() {
+            "<p>No posts matched your search.</p>".to_string()
+        } else {
+            matches.join("<hr>")
+        }
+    };
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>Search - {}</title>
+    <link rel="stylesheet" href="/static/style.css">
+</head>
+<body>
+    {}
+    <div class="board-nav"><a href="{}">Back to Board</a></div>
+    <hr>
+
+    <!-- Search Form -->
+    <form class="postform" action="{}search" method="get">
+        <input type="text" name="q" value="{}" placeholder="Search" aria-label="Search">
+        <input type="submit" value="Search">
+    </form>
+    <hr>
+
+    <!-- Search Results -->
+    <div class="postlists">
+        {}
+    </div>
+
+    <div class="footer">
+        - Powered by Rust and Actix Web -
+    </div>
+</body>
+</html>"#,
+        escape_html(&board_title(config, board)),
+        render_logo(config, &board_title(config, board)),
+        board_path(board),
+        board_path(board),
+        escape_html(&search_query),
+        results_html
+    );
+
+    HttpResponse::Ok().content_type("text/html").body(html)
+}
+
+// Handler for the latest replies across every thread on every board
+async fn recent_replies(db: web::Data<Arc<Db>>, config: web::Data<BoardConfig>) -> impl Responder {
+    let mut replies: Vec<(&str, i32, Reply)> = all_boards()
+        .flat_map(|board| {
+            get_all_replies(&db, board)
+                .into_iter()
+                .map(move |(thread_id, reply)| (board, thread_id, reply))
+        })
+        .filter(|(_, _, reply)| !reply.deleted)
+        .collect();
+
+    // Only the newest few need ordering, so set them apart before sorting
+    if replies.len() > RECENT_REPLIES {
+        replies.select_nth_unstable_by_key(RECENT_REPLIES, |(_, _, reply)| std::cmp::Reverse(reply.created_at));
+        replies.truncate(RECENT_REPLIES);
+    }
+    replies.sort_by_key(|(_, _, reply)| std::cmp::Reverse(reply.created_at));
+
+    // Look up each listed thread's subject once
+    let mut subjects: HashMap<(&str, i32), String> = HashMap::new();
+    let items: Vec<String> = replies
+        .iter()
+        .map(|(board, thread_id, reply)| {
+            let subject = subjects.entry((*board, *thread_id)).or_insert_with(|| {
+                db.get(thread_key(board, *thread_id))
+                    .ok()
+                    .flatten()
+                    .and_then(|value| serde_json::from_slice::<Thread>(&value).ok())
+                    .map(|mut thread| {
+                        thread.redact_if_deleted();
+                        display_subject(&thread.title).to_string()
+                    })
+                    .unwrap_or_else(|| NO_SUBJECT.to_string())
+            });
+            // Replies on the extra boards are labelled with the board they were posted to
+            let board_label = if *board == MAIN_BOARD { String::new() } else { format!("/{}/ &raquo; ", board) };
+            format!(
+                r#"<li><span class="timestamp">{}</span> <a href="{}#reply-{}">{}{} &raquo; Reply {}</a> <span class="recent-excerpt">{}</span></li>"#,
+                format_timestamp(reply.created_at),
+                thread_path(board, *thread_id),
+                reply.id,
+                board_label,
+                escape_html(&truncate_chars(subject, 40)),
+                reply.id,
+                escape_html(&truncate_chars(&reply.message, 100))
+            )
+        })
+        .collect();
+
+    let list_html = if items.is_empty() {
+        "<p>No replies yet.</p>".to_string()
+    } else {
+        format!(r#"<ul class="recent-list">{}</ul>"#, items.join(""))
+    };
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>Recent Replies</title>
+    <link rel="stylesheet" href="/static/style.css">
+</head>
+<body>
+    {}
+    <div class="board-nav"><a href="/">Back to Main Board</a></div>
+    <hr>
+
+    <!-- Recent Replies -->
+    {}
+
+    <div class="footer">
+        - Powered by Rust and Actix Web -
+    </div>
+</body>
+</html>"#,
+        render_logo(&config, "Recent Replies"),
+        list_html
+    );
+
+    HttpResponse::Ok().content_type("text/html").body(html)
+}
+
+// Helper function to check whether a post's title or message contains the query, ignoring case
+fn post_matches(query: &str, post: &SearchPost) -> bool {
+    match post {
+        SearchPost::Thread(thread) => {
+            contains_ignore_case(&thread.title, query) || contains_ignore_case(&thread.message, query)
+        }
+        SearchPost::Reply { reply, .. } => contains_ignore_case(&reply.message, query),
+    }
+}
+
+// Helper function to render a single search hit with the matched text highlighted
+fn render_search_result(board: &str, query: &str, post: &SearchPost) -> String {
+    let (class, header, link, message) = match post {
+        SearchPost::Thread(thread) => (
+            "thread-post",
+            format!(r#"<span class="title">{}</span>"#, highlight_matches(display_subject(&thread.title), query)),
+            thread_path(board, thread.id),
+            &thread.message,
+        ),
+        SearchPost::Reply { thread_id, reply } => (
+            "reply-post",
+            format!(r#"<span class="title">Reply {} in thread {}</span>"#, reply.id, thread_id),
+            format!("{}#reply-{}", thread_path(board, *thread_id), reply.id),
+            &reply.message,
+        ),
+    };
+
+    format!(
+        r#"<div class="post {}">
+    <div class="post-content">
+        <div class="post-header">
+            {}
+            <a href="{}" class="reply-link">View</a>
+        </div>
+        <div class="message">{}</div>
+    </div>
+</div>"#,
+        class,
+        header,
+        link,
+        highlight_matches(message, query)
+    )
+}
+
+// Helper function to check for a case-insensitive substring
+fn contains_ignore_case(text: &str, query: &str) -> bool {
+    text.char_indices()
+        .any(|(index, _)| match_len_ignore_case(&text[index..], query).is_some())
+}
+
+// Helper function to escape text while wrapping every case-insensitive match in <mark>
+fn highlight_matches(text: &str, query: &str) -> String {
+    let mut output = String::new();
+    let mut plain_start = 0;
+    let mut index = 0;
+
+    while index < text.len() {
+        if let Some(length) = match_len_ignore_case(&text[index..], query) {
+            output.push_str(&escape_html(&text[plain_start..index]));
+            output.push_str(&format!("<mark>{}</mark>", escape_html(&text[index..index + length])));
+            index += length;
+            plain_start = index;
+        } else {
+            index += text[index..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    output.push_str(&escape_html(&text[plain_start..]));
+
+    output
+}
+
+// Helper function returning the byte length of the start of text that matches
+// the query case-insensitively, or None if text does not start with the query
+fn match_len_ignore_case(text: &str, query: &str) -> Option<usize> {
+    let mut query_chars = query.chars().flat_map(char::to_lowercase).peekable();
+    query_chars.peek()?;
+
+    let mut consumed = 0;
+    for c in text.chars() {
+        if query_chars.peek().is_none() {
+            break;
+        }
+        for lower in c.to_lowercase() {
+            if query_chars.next() != Some(lower) {
+                return None;
+            }
+        }
+        consumed += c.len_utf8();
+    }
+
+    query_chars.peek().is_none().then_some(consumed)
+}
+
+// Handler to view a specific thread and its replies
+async fn view_thread(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    config: web::Data<BoardConfig>,
+    captchas: web::Data<Captchas>,
+    path: web::Path<(i32,)>,
+    query: web::Query<ThreadViewParams>,
+) -> impl Responder {
+    let view = ReplyView::Page(query.page);
+    render_thread_view(&req, &db, &config, &captchas, MAIN_BOARD, path.into_inner().0, view, query.after, query.quote)
+}
+
+// Handler to view a thread with only its most recent replies
+async fn view_thread_last(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    config: web::Data<BoardConfig>,
+    captchas: web::Data<Captchas>,
+    path: web::Path<(i32, usize)>,
+) -> impl Responder {
+    let (thread_id, count) = path.into_inner();
+    render_thread_view(&req, &db, &config, &captchas, MAIN_BOARD, thread_id, ReplyView::Last(count), None, None)
+}
+
+// Handler to view a thread on one of the extra boards
+async fn board_thread(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    config: web::Data<BoardConfig>,
+    captchas: web::Data<Captchas>,
+    path: web::Path<(String, i32)>,
+    query: web::Query<ThreadViewParams>,
+) -> impl Responder {
+    let (board, thread_id) = path.into_inner();
+    match validate_board(&board) {
+        Some(board) if board != MAIN_BOARD => {
+            render_thread_view(&req, &db, &config, &captchas, board, thread_id, ReplyView::Page(query.page), query.after, query.quote)
+        }
+        _ => Err(board_not_found_error()),
+    }
+}
+
+// Handler to view a thread on one of the extra boards with only its most recent replies
+async fn board_thread_last(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    config: web::Data<BoardConfig>,
+    captchas: web::Data<Captchas>,
+    path: web::Path<(String, i32, usize)>,
+) -> impl Responder {
+    let (board, thread_id, count) = path.into_inner();
+    match validate_board(&board) {
+        Some(board) if board != MAIN_BOARD => {
+            render_thread_view(&req, &db, &config, &captchas, board, thread_id, ReplyView::Last(count), None, None)
+        }
+        _ => Err(board_not_found_error()),
+    }
+}
+
+// Handler streaming a thread's new replies as server-sent events
+async fn thread_events_stream(
+    db: web::Data<Arc<Db>>,
+    thread_events: web::Data<ThreadEvents>,
+    path: web::Path<(i32,)>,
+) -> impl Responder {
+    stream_thread_events(&db, &thread_events, MAIN_BOARD, path.into_inner().0)
+}
+
+// Handler streaming the new replies of a thread on one of the extra boards
+async fn board_thread_events_stream(
+    db: web::Data<Arc<Db>>,
+    thread_events: web::Data<ThreadEvents>,
+    path: web::Path<(String, i32)>,
+) -> impl Responder {
+    let (board, thread_id) = path.into_inner();
+    match validate_board(&board) {
+        Some(board) if board != MAIN_BOARD => stream_thread_events(&db, &thread_events, board, thread_id),
+        _ => Err(board_not_found_error()),
+    }
+}
+
+// Helper function to answer with an event stream that sends each new reply's HTML as a "reply" event.
+// The stream ends when the client disconnects, which is noticed at the latest on the next keep-alive.
+fn stream_thread_events(db: &Db, thread_events: &ThreadEvents, board: &str, thread_id: i32) -> Result<HttpResponse, BoardError> {
+    if !db.contains_key(thread_key(board, thread_id)).unwrap_or(false) {
+        return Err(thread_not_found_error());
+    }
+
+    let receiver = subscribe_thread_events(thread_events, board, thread_id);
+    let events = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            let message = match tokio::time::timeout(EVENT_KEEPALIVE_INTERVAL, receiver.recv()).await {
+                Ok(Ok(event)) => event,
+                // A listener too slow to keep up skips the replies it missed
+                Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+                Ok(Err(broadcast::error::RecvError::Closed)) => return None,
+                Err(_) => ": keep-alive\n\n".to_string(),
+            };
+            return Some((Ok::<_, Error>(web::Bytes::from(message)), receiver));
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        // Compressing would hold events back until enough of them fill a block
+        .insert_header(header::ContentEncoding::Identity)
+        .streaming(events))
+}
+
+// Helper function to render a thread and a page or the tail of its replies
+#[allow(clippy::too_many_arguments)]
+fn render_thread_view(
+    req: &HttpRequest,
+    db: &Db,
+    config: &BoardConfig,
+    captchas: &Captchas,
+    board: &str,
+    thread_id: i32,
+    view: ReplyView,
+    after: Option<i32>,
+    quote: Option<i32>,
+) -> Result<HttpResponse, BoardError> {
+    let thread_key = thread_key(board, thread_id);
+    let thread: Option<Thread> = db.get(&thread_key).ok().flatten().and_then(|value| {
+        serde_json::from_slice(&value).ok()
+    });
+
+    if thread.is_none() {
+        return Err(thread_not_found_error());
+    }
+
+    // A deleted opening post shows only its placeholder above the remaining replies,
+    // along with buttons to restore deleted posts when an admin is viewing
+    let admin = is_admin(req);
+    let mut thread = thread.unwrap();
+    let deleted = thread.deleted;
+    thread.redact_if_deleted();
+    let thread_url = thread_path(board, thread_id);
+    let replies = get_replies(db, board, thread_id);
+    let reply_count = replies.len();
+
+    // Only the replies are paginated or cut, the original post always stays on top
+    let (replies, pagination_html, shows_newest) = match view {
+        ReplyView::Page(requested_page) => {
+            // Coming back with ?after= but no page opens the page where the new replies start
+            let requested_page = requested_page.or_else(|| {
+                let after = after?;
+                let first_new = replies.iter().position(|reply| reply.id > after)?;
+                Some(first_new as i32 / REPLIES_PER_PAGE + 1)
+            });
+            let (page_number, total_pages, range) = paginate(replies.len(), requested_page, REPLIES_PER_PAGE);
+            (&replies[range], render_pagination(&thread_url, page_number, total_pages), page_number >= total_pages)
+        }
+        ReplyView::Last(count) => {
+            let count = count.clamp(1, MAX_LAST_REPLIES);
+            let skip = replies.len().saturating_sub(count);
+            let link_html = format!(
+                r#"<div class="pagination">Showing the last {} of {} replies. <a href="{}">View full thread</a></div>"#,
+                replies.len() - skip,
+                replies.len(),
+                thread_url
+            );
+            (&replies[skip..], link_html, true)
+        }
+    };
+
+    // Generate HTML for the list of replies, with a divider before the first one newer than ?after=
+    let first_new_id = after.and_then(|after| replies.iter().find(|reply| reply.id > after)).map(|reply| reply.id);
+    let replies_html = if replies.is_empty() {
+        "<p>No replies yet. Be the first to reply!</p>".to_string()
+    } else {
+        replies
+            .iter()
+            .map(|reply| {
+                let html = render_reply(board, thread_id, reply, thread.poster_id.as_deref(), admin);
+                if Some(reply.id) == first_new_id {
+                    format!(r#"<div id="new-replies" class="new-replies">New replies</div>{}"#, html)
+                } else {
+                    html
+                }
+            })
+            .collect::<Vec<String>>()
+            .join("<hr>")
+    };
+
+    // Generate HTML for the thread's media if it exists
+    let media_html = format!(
+        "{}{}",
+        render_media(&thread.media_url, &thread.media_type, thread.spoiler, "Thread Image"),
+        render_media_info(&thread.media_info)
+    );
+
+    // Locked and full threads show a notice where the reply form would be
+    let closed_notice = if deleted {
+        "Thread deleted. No new replies can be posted.".to_string()
+    } else if is_read_only(db) {
+        READ_ONLY_NOTICE.to_string()
+    } else if thread.locked && thread.inactive_lock {
+        "Thread locked due to inactivity. No new replies can be posted.".to_string()
+    } else if thread.locked {
+        "Thread locked. No new replies can be posted.".to_string()
+    } else if reply_count >= MAX_REPLIES as usize {
+        format!("Thread full. It has reached the limit of {} replies.", MAX_REPLIES)
+    } else {
+        String::new()
+    };
+    let captcha = closed_notice.is_empty().then(|| new_captcha(captchas));
+
+    // Assemble the complete HTML for the thread view
+    let html = ThreadTemplate {
+        title: display_subject(&thread.title),
+        board_url: &board_path(board),
+        board_name: &if board == MAIN_BOARD { "Main Board".to_string() } else { board_title(config, board) },
+        board,
+        thread_id: thread.id,
+        captcha_question: captcha.as_ref().map(|(_, question)| question.as_str()),
+        closed_notice: &closed_notice,
+        uploads_enabled: !config.uploads_disabled,
+        upload_accept: &upload_accept(config),
+        media_html: &media_html,
+        poster_html: &if deleted { String::new() } else { render_poster(&thread.name, &thread.tripcode, &thread.poster_id, &thread.country) },
+        timestamp: &format_timestamp(thread.created_at),
+        message_html: &render_message(&thread.message, &thread_url),
+        report_form_html: &if deleted { String::new() } else { render_report_form(board, thread.id, 0) },
+        delete_form_html: &if deleted && admin {
+            render_restore_form(board, thread.id, 0)
+        } else {
+            render_delete_form(board, thread.id, 0, !deleted && thread.delete_password_hash.is_some())
+        },
+        replies_html: &replies_html,
+        events_url: shows_newest.then(|| format!("{}/events", thread_url)).as_deref(),
+        pagination_html: &pagination_html,
+        og_html: &render_og_tags(req, &thread),
+        logo_html: &render_logo(config, &board_title(config, board)),
+        message_prefill: &quote.filter(|reply_id| *reply_id > 0).map(|reply_id| format!(">>{}\n", reply_id)).unwrap_or_default(),
+    }
+    .render()
+    .expect("Failed to render thread view");
+
+    let mut response = HttpResponse::Ok();
+    if let Some((token, _)) = captcha {
+        response.cookie(captcha_cookie(token));
+    }
+    Ok(response.content_type("text/html").body(html))
+}
+
+// Helper function to render the collapsible report form under a post
+fn render_report_form(board: &str, thread_id: i32, reply_id: i32) -> String {
+    format!(
+        r#"<details class="report">
+            <summary>Report</summary>
+            <form action="/report" method="post">
+                <input type="hidden" name="board" value="{}">
+                <input type="hidden" name="thread_id" value="{}">
+                <input type="hidden" name="reply_id" value="{}">
+                <input type="text" name="reason" maxlength="{}" placeholder="Reason" required aria-label="Report reason">
+                <input type="submit" value="Send Report">
+            </form>
+        </details>"#,
+        escape_html(board),
+        thread_id,
+        reply_id,
+        MAX_REPORT_REASON_CHARS
+    )
+}
+
+// Helper function to render the latest replies shown under a thread on a board index
+fn render_reply_previews(board: &str, thread: &Thread, reply_count: i32, latest_replies: &[Reply]) -> String {
+    if latest_replies.is_empty() {
+        return String::new();
+    }
+
+    let omitted = reply_count - latest_replies.len() as i32;
+    let omitted_html = if omitted > 0 {
+        format!(
+            r#"<div class="omitted">{} omitted. <a href="{}">Click here</a> to view.</div>"#,
+            format_reply_count(omitted),
+            thread_path(board, thread.id)
+        )
+    } else {
+        String::new()
+    };
+
+    format!(
+        r#"<div class="reply-previews">{}{}</div>"#,
+        omitted_html,
+        latest_replies
+            .iter()
+            .map(|reply| render_reply(board, thread.id, reply, thread.poster_id.as_deref(), false))
+            .collect::<Vec<String>>()
+            .join("")
+    )
+}
+
+// Helper function to render the collapsible form posters use to delete their own post.
+// Posts made without a deletion password get no form.
+fn render_delete_form(board: &str, thread_id: i32, reply_id: i32, has_password: bool) -> String {
+    if !has_password {
+        return String::new();
+    }
+
+    format!(
+        r#"<details class="delete-post">
+            <summary>Delete</summary>
+            <form action="/delete" method="post">
+                <input type="hidden" name="board" value="{}">
+                <input type="hidden" name="thread_id" value="{}">
+                <input type="hidden" name="reply_id" value="{}">
+                <input type="password" name="password" maxlength="{}" placeholder="Deletion password" required aria-label="Deletion password">
+                <input type="submit" value="Delete Post">
+            </form>
+        </details>"#,
+        escape_html(board),
+        thread_id,
+        reply_id,
+        MAX_DELETE_PASSWORD_CHARS
+    )
+}
+
+// Helper function to render the admin button that brings back a deleted post
+fn render_restore_form(board: &str, thread_id: i32, reply_id: i32) -> String {
+    format!(
+        r#"<form action="/admin/restore" method="post" class="inline-form">
+            <input type="hidden" name="board" value="{}">
+            <input type="hidden" name="thread_id" value="{}">
+            <input type="hidden" name="reply_id" value="{}">
+            <input type="submit" value="Restore Post">
+        </form>"#,
+        escape_html(board),
+        thread_id,
+        reply_id
+    )
+}
+
+// Helper function to render individual replies.
+// op_poster_id is the thread's poster ID, used to mark replies by the original poster.
+// Admins get a button to restore deleted replies.
+fn render_reply(board: &str, thread_id: i32, reply: &Reply, op_poster_id: Option<&str>, admin: bool) -> String {
+    // Deleted replies keep their place in the thread as a placeholder
+    if reply.deleted {
+        return format!(
+            r#"<div class="post reply-post deleted-post" id="reply-{}">
+    <div class="post-content">
+        <div class="post-header">
+            <span class="title">Reply {}</span>
+            <span class="timestamp">{}</span>
+        </div>
+        <div class="message">{}</div>
+        {}
+    </div>
+</div>"#,
+            reply.id,
+            reply.id,
+            format_timestamp(reply.created_at),
+            DELETED_PLACEHOLDER,
+            if admin { render_restore_form(board, thread_id, reply.id) } else { String::new() }
+        );
+    }
+
+    let thread_url = thread_path(board, thread_id);
+
+    // Posts made before poster IDs were recorded have none and never count as the OP's
+    let is_op = op_poster_id.is_some() && reply.poster_id.as_deref() == op_poster_id;
+    let media_html = format!(
+        "{}{}",
+        render_media(&reply.media_url, &reply.media_type, reply.spoiler, "Reply Image"),
+        render_media_info(&reply.media_info)
+    );
+
+    format!(
+        r#"<div class="post reply-post" id="reply-{}">
+    {}
+    <div class="post-content">
+        <div class="post-header">
+            <span class="title">Reply {}</span>
+            {}{}
+            <span class="timestamp">{}</span>
+            <a class="reply-link" href="{}?quote={}#message">Quote</a>
+        </div>
+        <div class="message">{}</div>
+        {}{}
+    </div>
+</div>"#,
+        reply.id,
+        media_html,
+        reply.id,
+        render_poster(&reply.name, &reply.tripcode, &reply.poster_id, &reply.country),
+        if is_op { r#"<span class="op-marker">(OP)</span>"# } else { "" },
+        format_timestamp(reply.created_at),
+        thread_url,
+        reply.id,
+        render_message(&reply.message, &thread_url),
+        render_report_form(board, thread_id, reply.id),
+        render_delete_form(board, thread_id, reply.id, reply.delete_password_hash.is_some())
+    )
+}
+
+// API handler returning a page of threads as JSON along with pagination details.
+// Pages work like the homepage's: ?page= defaults to 1 and is clamped to the pages that exist.
+async fn api_threads(
+    db: web::Data<Arc<Db>>,
+    config: web::Data<BoardConfig>,
+    query: web::Query<PaginationParams>,
+) -> HttpResponse {
+    api_threads_on_board(&db, &config, MAIN_BOARD, query.page)
+}
+
+// API handler returning a page of threads on one of the extra boards as JSON
+async fn board_api_threads(
+    db: web::Data<Arc<Db>>,
+    config: web::Data<BoardConfig>,
+    path: web::Path<(String,)>,
+    query: web::Query<PaginationParams>,
+) -> HttpResponse {
+    match validate_board(&path.into_inner().0) {
+        Some(board) if board != MAIN_BOARD => api_threads_on_board(&db, &config, board, query.page),
+        _ => json_error_response(&board_not_found_error()),
+    }
+}
+
+// Helper function to answer with a page of a board's threads as JSON
+fn api_threads_on_board(db: &Db, config: &BoardConfig, board: &str, page: Option<i32>) -> HttpResponse {
+    let page_size = config.page_size;
+
+    let mut threads = get_all_threads_for_board(db, board);
+    sort_threads_by_bump(&mut threads);
+
+    let total_threads = threads.len();
+    let (page, total_pages, range) = paginate(total_threads, page, page_size);
+    let body = ThreadPage {
+        threads: threads[range].iter().cloned().map(ApiThread::from).collect(),
+        pagination: PaginationInfo {
+            total_threads,
+            page,
+            total_pages,
+            page_size,
+        },
+    };
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(serde_json::to_string(&body).expect("Failed to serialize threads"))
+}
+
+// API handler returning a single thread and its replies as JSON
+async fn api_thread(
+    db: web::Data<Arc<Db>>,
+    path: web::Path<(i32,)>,
+) -> HttpResponse {
+    api_thread_on_board(&db, MAIN_BOARD, path.into_inner().0)
+}
+
+// API handler returning a single thread on one of the extra boards and its replies as JSON
+async fn board_api_thread(
+    db: web::Data<Arc<Db>>,
+    path: web::Path<(String, i32)>,
+) -> HttpResponse {
+    let (board, thread_id) = path.into_inner();
+    match validate_board(&board) {
+        Some(board) if board != MAIN_BOARD => api_thread_on_board(&db, board, thread_id),
+        _ => json_error_response(&board_not_found_error()),
+    }
+}
+
+// Helper function to answer with a thread on a board and its replies as JSON
+fn api_thread_on_board(db: &Db, board: &str, thread_id: i32) -> HttpResponse {
+    let thread_key = thread_key(board, thread_id);
+    let thread: Option<Thread> = db.get(&thread_key).ok().flatten().and_then(|value| {
+        serde_json::from_slice(&value).ok()
+    });
+
+    match thread {
+        Some(thread) => {
+            // Never hand out password hashes or the content of deleted posts
+            let mut replies = get_replies(db, board, thread_id);
+            for reply in &mut replies {
+                reply.delete_password_hash = None;
+                reply.redact_if_deleted();
+            }
+            let body = ThreadWithReplies { thread: thread.into(), replies };
+            HttpResponse::Ok()
+                .content_type("application/json")
+                .body(serde_json::to_string(&body).expect("Failed to serialize thread"))
+        }
+        None => HttpResponse::NotFound()
+            .content_type("application/json")
+            .body(json_error("Thread not found")),
+    }
+}
+
+// API handler returning a single reply as JSON
+async fn api_reply(
+    db: web::Data<Arc<Db>>,
+    path: web::Path<(i32, i32)>,
+) -> HttpResponse {
+    let (parent_id, reply_id) = path.into_inner();
+    api_reply_on_board(&db, MAIN_BOARD, parent_id, reply_id)
+}
+
+// API handler returning a single reply on one of the extra boards as JSON
+async fn board_api_reply(
+    db: web::Data<Arc<Db>>,
+    path: web::Path<(String, i32, i32)>,
+) -> HttpResponse {
+    let (board, parent_id, reply_id) = path.into_inner();
+    match validate_board(&board) {
+        Some(board) if board != MAIN_BOARD => api_reply_on_board(&db, board, parent_id, reply_id),
+        _ => json_error_response(&board_not_found_error()),
+    }
+}
+
+// Helper function to answer with a reply on a board as JSON
+fn api_reply_on_board(db: &Db, board: &str, parent_id: i32, reply_id: i32) -> HttpResponse {
+    let reply: Option<Reply> = db.get(reply_key(board, parent_id, reply_id)).ok().flatten().and_then(|value| {
+        serde_json::from_slice(&value).ok()
+    });
+
+    match reply {
+        Some(mut reply) => {
+            // Never hand out password hashes or the content of deleted posts
+            reply.delete_password_hash = None;
+            reply.redact_if_deleted();
+            HttpResponse::Ok()
+                .content_type("application/json")
+                .body(serde_json::to_string(&reply).expect("Failed to serialize reply"))
+        }
+        None => HttpResponse::NotFound()
+            .content_type("application/json")
+            .body(json_error("Reply not found")),
+    }
+}
+
+// Handler for load balancer liveness checks; answers as long as the server is running
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(serde_json::json!({ "status": "ok" }).to_string())
+}
+
+// Handler for readiness checks, verifying that the database still answers reads
+async fn readyz(db: web::Data<Arc<Db>>) -> impl Responder {
+    let thread_count = all_boards().try_fold(0usize, |count, board| {
+        let prefix = format!("{}thread_", board_key_prefix(board));
+        db.scan_prefix(prefix).keys().try_fold(count, |count, key| key.map(|_| count + 1))
+    });
+
+    match thread_count {
+        Ok(thread_count) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(serde_json::json!({ "status": "ready", "database": "ok", "threads": thread_count }).to_string()),
+        Err(e) => {
+            error!("Readiness check failed to read sled db: {}", e);
+            HttpResponse::ServiceUnavailable()
+                .content_type("application/json")
+                .body(serde_json::json!({ "status": "unavailable", "database": "error" }).to_string())
+        }
+    }
+}
+
+// Handler for Prometheus scrapes. Post counters are kept in memory since startup,
+// while the stored thread and reply totals are counted from the database.
+async fn metrics_endpoint(db: web::Data<Arc<Db>>, metrics: web::Data<Arc<Metrics>>) -> impl Responder {
+    let mut body = String::new();
+    let mut counter = |name: &str, help: &str, value: u64| {
+        body.push_str(&format!("# HELP {} {}\n# TYPE {} counter\n{} {}\n", name, help, name, name, value));
+    };
+    counter("imageboard_http_requests_total", "HTTP requests received.", metrics.http_requests.load(Ordering::Relaxed));
+    counter("imageboard_threads_created_total", "Threads created.", metrics.threads_created.load(Ordering::Relaxed));
+    counter("imageboard_replies_created_total", "Replies created.", metrics.replies_created.load(Ordering::Relaxed));
+    counter(
+        "imageboard_skipped_records_total",
+        "Stored records skipped because they could not be decoded.",
+        SKIPPED_RECORDS.load(Ordering::Relaxed),
+    );
+
+    body.push_str("# HELP imageboard_uploads_total Media files attached to new posts.\n");
+    body.push_str("# TYPE imageboard_uploads_total counter\n");
+    for (media_type, uploads) in [
+        ("image", &metrics.image_uploads),
+        ("video", &metrics.video_uploads),
+        ("audio", &metrics.audio_uploads),
+    ] {
+        body.push_str(&format!(
+            "imageboard_uploads_total{{media_type=\"{}\"}} {}\n",
+            media_type,
+            uploads.load(Ordering::Relaxed)
+        ));
+    }
+
+    for (name, help, kind) in [
+        ("imageboard_threads", "Threads currently stored.", "thread_"),
+        ("imageboard_replies", "Replies currently stored.", "reply_"),
+    ] {
+        body.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n", name, help, name));
+        for board in all_boards() {
+            let count = db.scan_prefix(format!("{}{}", board_key_prefix(board), kind)).keys().count();
+            let label = if board == MAIN_BOARD { "main" } else { board };
+            body.push_str(&format!("{}{{board=\"{}\"}} {}\n", name, label, count));
+        }
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(body)
+}
+
+// Helper function to build a JSON error body
+fn json_error(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+// Helper function to answer an API request with an error's status code and message as JSON
+fn json_error_response(e: &dyn ResponseError) -> HttpResponse {
+    let page = e.error_response();
+    let mut response = HttpResponse::build(page.status());
+    if let Some(retry_after) = page.headers().get(header::RETRY_AFTER) {
+        response.insert_header((header::RETRY_AFTER, retry_after.clone()));
+    }
+    response.content_type("application/json").body(json_error(&e.to_string()))
+}
+
+// Handler for the RSS 2.0 feed of the most recently bumped threads on the main board
+async fn rss_feed(req: HttpRequest, db: web::Data<Arc<Db>>, config: web::Data<BoardConfig>) -> impl Responder {
+    render_board_feed(&req, &db, &config, MAIN_BOARD)
+}
+
+// Handler for the RSS 2.0 feed of one of the extra boards
+async fn board_rss_feed(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    config: web::Data<BoardConfig>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, BoardError> {
+    match validate_board(&path.into_inner()) {
+        Some(board) if board != MAIN_BOARD => Ok(render_board_feed(&req, &db, &config, board)),
+        _ => Err(board_not_found_error()),
+    }
+}
+
+// Helper function to render the RSS 2.0 feed of a board's most recently bumped threads
+fn render_board_feed(req: &HttpRequest, db: &Db, config: &BoardConfig, board: &str) -> HttpResponse {
+    let mut threads = get_all_threads_for_board(db, board);
+    threads.sort_by_key(|thread| std::cmp::Reverse(thread.last_updated));
+
+    // Feed readers need absolute links, so build them from the host the feed was requested on
+    let connection = req.connection_info();
+    let base_url = format!("{}://{}", connection.scheme(), connection.host());
+
+    let items = threads
+        .iter()
+        .filter(|thread| !thread.deleted)
+        .take(FEED_ITEMS)
+        .map(|thread| {
+            let link = format!("{}{}", base_url, thread_path(board, thread.id));
+            let timestamp = if thread.created_at > 0 { thread.created_at } else { thread.last_updated };
+            let pub_date = chrono::DateTime::from_timestamp(timestamp, 0)
+                .map(|time| format!("\n      <pubDate>{}</pubDate>", time.to_rfc2822()))
+                .unwrap_or_default();
+            format!(
+                r#"    <item>
+      <title>{}</title>
+      <link>{}</link>
+      <guid>{}</guid>
+      <description>{}</description>{}
+    </item>"#,
+                escape_xml(display_subject(&thread.title)),
+                escape_xml(&link),
+                escape_xml(&link),
+                escape_xml(&thread.message),
+                pub_date
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let xml = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0">
+  <channel>
+    <title>{}</title>
+    <link>{}{}</link>
+    <description>Recently bumped threads</description>
+{}
+  </channel>
+</rss>"#,
+        escape_xml(&board_title(config, board)),
+        escape_xml(&base_url),
+        board_path(board),
+        items
+    );
+
+    HttpResponse::Ok().content_type("application/rss+xml").body(xml)
+}
+
+// Handler for robots.txt, keeping crawlers out of the admin pages and pointing them at the sitemap.
+// ROBOTS_DISALLOW_ALL turns every crawler away instead.
+async fn robots_txt(req: HttpRequest, config: web::Data<BoardConfig>) -> impl Responder {
+    let body = if config.robots_disallow_all {
+        "User-agent: *\nDisallow: /\n".to_string()
+    } else {
+        let connection = req.connection_info();
+        format!(
+            "User-agent: *\nDisallow: /admin\nAllow: /\n\nSitemap: {}://{}/sitemap.xml\n",
+            connection.scheme(),
+            connection.host()
+        )
+    };
+
+    HttpResponse::Ok().content_type("text/plain; charset=utf-8").body(body)
+}
+
+// Helper function to collect the path and last update time of every live thread on every board,
+// most recently bumped first
+fn sitemap_entries(db: &Db) -> Vec<(String, i64)> {
+    let mut entries: Vec<(String, i64)> = std::iter::once(MAIN_BOARD)
+        .chain(BOARDS.iter().map(|(slug, _)| *slug))
+        .flat_map(|board| {
+            get_all_threads_for_board(db, board)
+                .into_iter()
+                .filter(|thread| !thread.deleted)
+                .map(move |thread| (thread_path(board, thread.id), thread.last_updated))
+        })
+        .collect();
+    entries.sort_by_key(|(_, last_updated)| std::cmp::Reverse(*last_updated));
+    entries
+}
+
+// Helper function to render a sitemap <urlset> of thread paths
+fn render_sitemap_urlset(base_url: &str, entries: &[(String, i64)]) -> String {
+    let urls = entries
+        .iter()
+        .map(|(path, last_updated)| {
+            let lastmod = chrono::DateTime::from_timestamp(*last_updated, 0)
+                .map(|time| format!("<lastmod>{}</lastmod>", time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)))
+                .unwrap_or_default();
+            format!("  <url><loc>{}</loc>{}</url>", escape_xml(&format!("{}{}", base_url, path)), lastmod)
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+{}
+</urlset>"#,
+        urls
+    )
+}
+
+// Handler for /sitemap.xml, listing every thread or, past the per-file limit, the numbered sitemap pages
+async fn sitemap(req: HttpRequest, db: web::Data<Arc<Db>>) -> impl Responder {
+    let entries = sitemap_entries(&db);
+
+    // Search engines need absolute URLs, so build them from the host the sitemap was requested on
+    let connection = req.connection_info();
+    let base_url = format!("{}://{}", connection.scheme(), connection.host());
+
+    if entries.len() <= SITEMAP_MAX_URLS {
+        return HttpResponse::Ok().content_type("application/xml").body(render_sitemap_urlset(&base_url, &entries));
+    }
+
+    let sitemaps = (1..=entries.len().div_ceil(SITEMAP_MAX_URLS))
+        .map(|page| format!("  <sitemap><loc>{}</loc></sitemap>", escape_xml(&format!("{}/sitemap-{}.xml", base_url, page))))
+        .collect::<Vec<String>>()
+        .join("\n");
+    let xml = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+{}
+</sitemapindex>"#,
+        sitemaps
+    );
+
+    HttpResponse::Ok().content_type("application/xml").body(xml)
+}
+
+// Handler for one numbered page of the sitemap, linked from the sitemap index
+async fn sitemap_page(req: HttpRequest, db: web::Data<Arc<Db>>, path: web::Path<(usize,)>) -> Result<HttpResponse, BoardError> {
+    let page = path.into_inner().0;
+    let entries = sitemap_entries(&db);
+    let start = page.saturating_sub(1).saturating_mul(SITEMAP_MAX_URLS);
+    if page == 0 || start >= entries.len() {
+        return Err(BoardError::NotFound("Sitemap Not Found", "The requested sitemap page does not exist.".to_string()));
+    }
+
+    let connection = req.connection_info();
+    let base_url = format!("{}://{}", connection.scheme(), connection.host());
+    let end = (start + SITEMAP_MAX_URLS).min(entries.len());
+
+    Ok(HttpResponse::Ok().content_type("application/xml").body(render_sitemap_urlset(&base_url, &entries[start..end])))
+}
+
+// Handler for the Atom feed of a thread's replies
+async fn thread_feed(req: HttpRequest, db: web::Data<Arc<Db>>, path: web::Path<(i32,)>) -> impl Responder {
+    render_thread_feed(&req, &db, MAIN_BOARD, path.into_inner().0)
+}
+
+// Handler for the Atom feed of a thread's replies on one of the extra boards
+async fn board_thread_feed(req: HttpRequest, db: web::Data<Arc<Db>>, path: web::Path<(String, i32)>) -> impl Responder {
+    let (board, thread_id) = path.into_inner();
+    match validate_board(&board) {
+        Some(board) if board != MAIN_BOARD => render_thread_feed(&req, &db, board, thread_id),
+        _ => Err(board_not_found_error()),
+    }
+}
+
+// Helper function to format a Unix timestamp as an Atom date
+fn atom_timestamp(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .unwrap_or_default()
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+// Helper function to render the Atom feed of a thread, one entry per reply linking to its anchor
+fn render_thread_feed(req: &HttpRequest, db: &Db, board: &str, thread_id: i32) -> Result<HttpResponse, BoardError> {
+    let thread: Option<Thread> = db
+        .get(thread_key(board, thread_id))
+        .ok()
+        .flatten()
+        .and_then(|value| serde_json::from_slice(&value).ok());
+    let thread = match thread {
+        Some(thread) if !thread.deleted => thread,
+        _ => {
+            return Err(thread_not_found_error());
+        }
+    };
+
+    // Feed readers need absolute links, so build them from the host the feed was requested on
+    let connection = req.connection_info();
+    let thread_url = format!("{}://{}{}", connection.scheme(), connection.host(), thread_path(board, thread_id));
+
+    // Replies made before timestamps were recorded fall back to the thread's creation time
+    let thread_created = if thread.created_at > 0 { thread.created_at } else { thread.last_updated };
+    let replies: Vec<Reply> = get_replies(db, board, thread_id).into_iter().filter(|reply| !reply.deleted).collect();
+    let entries = replies
+        .iter()
+        .map(|reply| {
+            let link = format!("{}#reply-{}", thread_url, reply.id);
+            let updated = if reply.created_at > 0 { reply.created_at } else { thread_created };
+            format!(
+                r#"  <entry>
+    <title>Reply {}</title>
+    <link href="{}"/>
+    <id>{}</id>
+    <updated>{}</updated>
+    <author><name>{}</name></author>
+    <content type="text">{}</content>
+  </entry>"#,
+                reply.id,
+                escape_xml(&link),
+                escape_xml(&link),
+                atom_timestamp(updated),
+                escape_xml(reply.name.as_deref().filter(|name| !name.is_empty()).unwrap_or(DEFAULT_NAME)),
+                escape_xml(&reply.message)
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let updated = replies
+        .iter()
+        .map(|reply| reply.created_at)
+        .max()
+        .filter(|created_at| *created_at > 0)
+        .unwrap_or(thread.last_updated);
+    let xml = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>{}</title>
+  <link href="{}"/>
+  <link rel="self" href="{}/feed.xml"/>
+  <id>{}</id>
+  <updated>{}</updated>
+{}
+</feed>"#,
+        escape_xml(display_subject(&thread.title)),
+        escape_xml(&thread_url),
+        escape_xml(&thread_url),
+        escape_xml(&thread_url),
+        atom_timestamp(updated),
+        entries
+    );
+
+    Ok(HttpResponse::Ok().content_type("application/atom+xml").body(xml))
+}
+
+// Handler to create a new thread with optional media upload
+#[allow(clippy::too_many_arguments)]
+async fn create_thread(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    config: web::Data<BoardConfig>,
+    paths: web::Data<DataPaths>,
+    post_times: web::Data<PostTimes>,
+    recent_content: web::Data<RecentContent>,
+    captchas: web::Data<Captchas>,
+    geoip: web::Data<GeoIp>,
+    metrics: web::Data<Arc<Metrics>>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    // Turn away banned clients and flooders before reading the upload
+    let ip = client_ip(&req);
+    check_not_read_only(&db)?;
+    check_not_banned(&db, &ip)?;
+    check_post_cooldown(&post_times, &ip)?;
+
+    let mut board = String::new();
+    let mut poster_name = String::new();
+    let mut captcha_answer = String::new();
+    let mut title = String::new();
+    let mut message = String::new();
+    let mut delete_password = String::new();
+    let mut spoiler = false;
+    let mut media_url: Option<String> = None;
+    let mut media_type: Option<MediaType> = None;
+    let mut thumb_url: Option<String> = None;
+    let mut media_info: Option<MediaInfo> = None;
+    let mut upload = PendingUpload::new(&db, &paths);
+
+    while let Some(item) = payload.next().await {
+        let mut field = item?;
+        let content_disposition = field.content_disposition();
+
+        let name = if let Some(name) = content_disposition.get_name() {
+            name
+        } else {
+            continue;
+        };
+
+        match name {
+            "board" => board.push_str(&read_text_field(&mut field).await?),
+            "name" => poster_name.push_str(&read_text_field(&mut field).await?),
+            "captcha" => captcha_answer.push_str(&read_text_field(&mut field).await?),
+            "title" => title.push_str(&read_text_field(&mut field).await?),
+            "message" => message.push_str(&read_text_field(&mut field).await?),
+            "delete_password" => delete_password.push_str(&read_text_field(&mut field).await?),
+            "spoiler" => {
+                // Present only when the "spoiler" checkbox is ticked
+                read_text_field(&mut field).await?;
+                spoiler = true;
+            }
+            "media" => {
+                // Handle media upload
+                if let Some(filename) = content_disposition.get_filename() {
+                    // Skip processing if filename is empty
+                    if filename.trim().is_empty() {
+                        continue;
+                    }
+                    if config.uploads_disabled {
+                        return Err(BoardError::BadRequest("Media uploads are disabled on this board".to_string()).into());
+                    }
+                    // Posts hold a single file, so a second one is refused
+                    if media_url.is_some() {
+                        return Err(BoardError::BadRequest("Only one file can be attached to a post".to_string()).into());
+                    }
+
+                    let filename = filename.to_string();
+                    if let Some(saved) = save_media_upload(&db, &paths, &config, &mut field, &filename).await? {
+                        upload.media_url = Some(saved.url.clone());
+                        upload.thumb_url = saved.thumb_url.clone();
+                        media_url = Some(saved.url);
+                        media_type = Some(saved.media_type);
+                        thumb_url = saved.thumb_url;
+                        media_info = saved.info;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    // The challenge is used up whether or not the answer is right
+    if !verify_captcha(&captchas, &req, &captcha_answer) {
+        return Err(captcha_failed_error().into());
+    }
+
+    let new_thread = NewThread {
+        board,
+        name: poster_name,
+        title,
+        message,
+        delete_password,
+        spoiler,
+        media_url,
+        media_type,
+        thumb_url,
+        media_info,
+        country: lookup_country(&geoip, &ip),
+        ip,
+    };
+    let (board, _) = submit_thread(&db, &config, &post_times, &recent_content, &metrics, new_thread).await?;
+    upload.keep();
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", board_path(board)))
+        .finish())
+}
+
+// Helper function to validate and store a new thread, shared by the HTML form and the JSON API.
+// Returns the board it was posted to along with the stored thread; callers only need to check
+// the CAPTCHA first.
+async fn submit_thread(
+    db: &Arc<Db>,
+    config: &BoardConfig,
+    post_times: &PostTimes,
+    recent_content: &RecentContent,
+    metrics: &Metrics,
+    new_thread: NewThread,
+) -> Result<(&'static str, Thread), Error> {
+    let ip = new_thread.ip.clone();
+
+    // Turn away the same message posted again by the same client
+    let content_hash = hash_content(&normalize_message(&new_thread.message));
+    check_not_duplicate(recent_content, &ip, &content_hash)?;
+
+    // Storing hashes the deletion password and reads the board, so keep it off the async workers
+    let (insert_db, config) = (db.clone(), config.clone());
+    let (board, thread) = web::block(move || insert_thread(&insert_db, &config, new_thread)).await??;
+    record_recent_content(recent_content, &ip, content_hash);
+    record_post_time(post_times, ip);
+    metrics.threads_created.fetch_add(1, Ordering::Relaxed);
+    metrics.record_upload(&thread.media_type);
+    Ok((board, thread))
+}
+
+// Function to validate a new thread, give it the board's next ID and store it.
+// Returns the board it was posted to along with the stored thread; nothing is written
+// when the thread is refused.
+fn insert_thread(db: &Db, config: &BoardConfig, new_thread: NewThread) -> Result<(&'static str, Thread), BoardError> {
+    let NewThread {
+        board,
+        name: poster_name,
+        title,
+        message,
+        delete_password,
+        spoiler,
+        media_url,
+        media_type,
+        thumb_url,
+        media_info,
+        ip,
+        country,
+    } = new_thread;
+    let message = normalize_message(&message);
+
+    // Only boards on the allow-list may be used in keys
+    let board = validate_board(board.trim()).ok_or_else(board_not_found_error)?;
+
+    // Ensure that the message is not empty, the subject is optional
+    if message.is_empty() {
+        return Err(BoardError::BadRequest("Message cannot be empty".to_string()));
+    }
+
+    // The forms' maxlength is only a hint, so enforce it for clients that skip them
+    check_text_length("Subject", &title, MAX_TITLE_CHARS)?;
+    check_text_length("Message", &message, MAX_MESSAGE_CHARS)?;
+    check_text_length("Deletion password", &delete_password, MAX_DELETE_PASSWORD_CHARS)?;
+
+    // Turn away clients that already have too many threads open on this board
+    let creator_hash = compute_creator_hash(db, &ip);
+    check_thread_limit(db, config, board, creator_hash.as_deref())?;
+
+    let delete_password_hash = hash_delete_password(&delete_password)?;
+    let thread_id = match next_thread_id(db, board) {
+        Ok(Some(thread_id)) => thread_id,
+        Ok(None) => {
+            return Err(BoardError::Unavailable("Board Full", "This board has run out of thread IDs.".to_string()));
+        }
+        Err(e) => {
+            error!("Failed to allocate a thread ID: {}", e);
+            return Err(BoardError::Internal("Failed to create thread".to_string()));
+        }
+    };
+    let (name, tripcode) = parse_name_and_tripcode(&poster_name);
+    let spoiler = spoiler && matches!(media_type, Some(MediaType::Image)); // Only images can be spoilered
+    let poster_id = compute_poster_id(db, &ip, board, thread_id);
+    let now = Utc::now().timestamp();
+    let thread = Thread {
+        id: thread_id,
+        title: title.trim().to_string(),
+        message,
+        last_updated: now,
+        created_at: now,
+        name,
+        tripcode,
+        media_url,
+        media_type,
+        thumb_url,
+        spoiler,
+        media_info,
+        sticky: false,
+        locked: false,
+        inactive_lock: false,
+        poster_id,
+        country,
+        delete_password_hash,
+        deleted: false,
+        creator_hash,
+    };
+
+    let value = serde_json::to_vec(&thread).expect("Failed to serialize thread");
+    db.insert(thread_key(board, thread_id), value).map_err(|e| {
+        error!("Failed to insert thread into sled db: {}", e);
+        BoardError::Internal("Failed to create thread".to_string())
+    })?;
+    Ok((board, thread))
+}
+
+// Handler to create a thread from a JSON or form-encoded body, answering with the new thread as JSON
+// and its API path in the Location header. API clients answer a challenge from /api/captcha,
+// sending back the cookie it set.
+#[allow(clippy::too_many_arguments)]
+async fn api_create_thread(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    config: web::Data<BoardConfig>,
+    post_times: web::Data<PostTimes>,
+    recent_content: web::Data<RecentContent>,
+    captchas: web::Data<Captchas>,
+    geoip: web::Data<GeoIp>,
+    metrics: web::Data<Arc<Metrics>>,
+    body: web::Either<web::Json<ApiThreadForm>, web::Form<ApiThreadForm>>,
+) -> HttpResponse {
+    let form = body.into_inner();
+    let ip = client_ip(&req);
+    if let Err(e) = check_not_read_only(&db)
+        .and_then(|_| check_not_banned(&db, &ip))
+        .and_then(|_| check_post_cooldown(&post_times, &ip))
+    {
+        return json_error_response(&e);
+    }
+
+    // The challenge is used up whether or not the answer is right
+    if !verify_captcha(&captchas, &req, &form.captcha) {
+        return json_error_response(&captcha_failed_error());
+    }
+
+    let new_thread = NewThread {
+        board: form.board,
+        name: form.name,
+        title: form.title,
+        message: form.message,
+        delete_password: form.delete_password,
+        spoiler: false,
+        media_url: None,
+        media_type: None,
+        thumb_url: None,
+        media_info: None,
+        country: lookup_country(&geoip, &ip),
+        ip,
+    };
+    match submit_thread(&db, &config, &post_times, &recent_content, &metrics, new_thread).await {
+        Ok((board, thread)) => {
+            HttpResponse::Created()
+                .append_header(("Location", api_thread_path(board, thread.id)))
+                .content_type("application/json")
+                .body(serde_json::to_string(&ApiThread::from(thread)).expect("Failed to serialize thread"))
+        }
+        Err(e) => json_error_response(e.as_response_error()),
+    }
+}
+
+// Handler to open a CAPTCHA challenge for API clients, setting the cookie the answer must be sent with
+async fn api_captcha(captchas: web::Data<Captchas>) -> HttpResponse {
+    let (token, question) = new_captcha(&captchas);
+    HttpResponse::Ok()
+        .cookie(captcha_cookie(token))
+        .content_type("application/json")
+        .body(serde_json::json!({ "question": question }).to_string())
+}
+
+// Handler to create a new reply to an existing thread with optional media upload
+#[allow(clippy::too_many_arguments)]
+async fn create_reply(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    config: web::Data<BoardConfig>,
+    paths: web::Data<DataPaths>,
+    post_times: web::Data<PostTimes>,
+    recent_content: web::Data<RecentContent>,
+    captchas: web::Data<Captchas>,
+    thread_events: web::Data<ThreadEvents>,
+    geoip: web::Data<GeoIp>,
+    metrics: web::Data<Arc<Metrics>>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    // Turn away banned clients and flooders before reading the upload
+    let ip = client_ip(&req);
+    check_not_read_only(&db)?;
+    check_not_banned(&db, &ip)?;
+    check_post_cooldown(&post_times, &ip)?;
+
+    let mut board = String::new();
+    let mut poster_name = String::new();
+    let mut captcha_answer = String::new();
+    let mut parent_id = String::new();
+    let mut message = String::new();
+    let mut delete_password = String::new();
+    let mut spoiler = false;
+    let mut sage = false;
+    let mut media_url: Option<String> = None;
+    let mut media_type: Option<MediaType> = None;
+    let mut thumb_url: Option<String> = None;
+    let mut media_info: Option<MediaInfo> = None;
+    let mut upload = PendingUpload::new(&db, &paths);
+
+    while let Some(item) = payload.next().await {
+        let mut field = item?;
+        let content_disposition = field.content_disposition();
+
+        let name = if let Some(name) = content_disposition.get_name() {
+            name
+        } else {
+            continue;
+        };
+
+        match name {
+            "board" => board.push_str(&read_text_field(&mut field).await?),
+            "name" => poster_name.push_str(&read_text_field(&mut field).await?),
+            "captcha" => captcha_answer.push_str(&read_text_field(&mut field).await?),
+            "parent_id" => parent_id.push_str(&read_text_field(&mut field).await?),
+            "message" => message.push_str(&read_text_field(&mut field).await?),
+            "delete_password" => delete_password.push_str(&read_text_field(&mut field).await?),
+            "spoiler" => {
+                // Present only when the "spoiler" checkbox is ticked
+                read_text_field(&mut field).await?;
+                spoiler = true;
+            }
+            "sage" => {
+                // Present only when the "sage" checkbox is ticked
+                read_text_field(&mut field).await?;
+                sage = true;
+            }
+            "media" => {
+                // Handle media upload
+                if let Some(filename) = content_disposition.get_filename() {
+                    // Skip processing if filename is empty
+                    if filename.trim().is_empty() {
+                        continue;
+                    }
+                    if config.uploads_disabled {
+                        return Err(BoardError::BadRequest("Media uploads are disabled on this board".to_string()).into());
+                    }
+                    // Posts hold a single file, so a second one is refused
+                    if media_url.is_some() {
+                        return Err(BoardError::BadRequest("Only one file can be attached to a post".to_string()).into());
+                    }
+
+                    let filename = filename.to_string();
+                    if let Some(saved) = save_media_upload(&db, &paths, &config, &mut field, &filename).await? {
+                        upload.media_url = Some(saved.url.clone());
+                        upload.thumb_url = saved.thumb_url.clone();
+                        media_url = Some(saved.url);
+                        media_type = Some(saved.media_type);
+                        thumb_url = saved.thumb_url;
+                        media_info = saved.info;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let parent_id: i32 = parent_id
+        .trim()
+        .parse()
+        .map_err(|_| BoardError::BadRequest("Invalid thread ID".to_string()))?;
+
+    // The challenge is used up whether or not the answer is right
+    if !verify_captcha(&captchas, &req, &captcha_answer) {
+        return Err(captcha_failed_error().into());
+    }
+
+    // Turn away the same message posted again by the same client
+    let content_hash = hash_content(&normalize_message(&message));
+    check_not_duplicate(&recent_content, &ip, &content_hash)?;
+
+    let new_reply = NewReply {
+        board,
+        parent_id,
+        name: poster_name,
+        message,
+        delete_password,
+        spoiler,
+        sage,
+        media_url,
+        media_type,
+        thumb_url,
+        media_info,
+        ip: ip.clone(),
+        country: lookup_country(&geoip, &ip),
+    };
+
+    // Storing hashes the deletion password and reads the thread, so keep it off the async workers
+    let insert_db = db.get_ref().clone();
+    let (board, parent, reply) = web::block(move || insert_reply(&insert_db, new_reply)).await??;
+    upload.keep();
+    record_recent_content(&recent_content, &ip, content_hash);
+    record_post_time(&post_times, ip);
+    metrics.replies_created.fetch_add(1, Ordering::Relaxed);
+    metrics.record_upload(&reply.media_type);
+
+    // Show the reply on pages watching the thread
+    let reply_html = render_reply(board, parent_id, &reply, parent.poster_id.as_deref(), false);
+    publish_thread_event(&thread_events, board, parent_id, format_sse_event("reply", &reply_html));
+
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", thread_path(board, parent_id)))
+        .finish())
+}
+
+// Function to validate a new reply, give it the thread's next reply ID and store it, bumping
+// the thread unless it has hit the bump limit or the reply is saged. Returns the board, the
+// thread as it was before the reply and the stored reply; nothing is written when the reply
+// is refused.
+fn insert_reply(db: &Db, new_reply: NewReply) -> Result<(&'static str, Thread, Reply), BoardError> {
+    let NewReply {
+        board,
+        parent_id,
+        name: poster_name,
+        message,
+        delete_password,
+        spoiler,
+        sage,
+        media_url,
+        media_type,
+        thumb_url,
+        media_info,
+        ip,
+        country,
+    } = new_reply;
+    let message = normalize_message(&message);
+
+    // Only boards on the allow-list may be used in keys
+    let board = validate_board(board.trim()).ok_or_else(board_not_found_error)?;
+
+    // Ensure that message is not empty
+    if message.is_empty() {
+        return Err(BoardError::BadRequest("Message cannot be empty".to_string()));
+    }
+
+    // The form's maxlength is only a hint, so enforce it for clients that skip it
+    check_text_length("Message", &message, MAX_MESSAGE_CHARS)?;
+    check_text_length("Deletion password", &delete_password, MAX_DELETE_PASSWORD_CHARS)?;
+
+    // Deleted, locked and full threads accept no new replies
+    let parent: Thread = db
+        .get(thread_key(board, parent_id))
+        .ok()
+        .flatten()
+        .and_then(|value| serde_json::from_slice(&value).ok())
+        .ok_or_else(|| BoardError::NotFound("Thread Not Found", "The requested thread does not exist.".to_string()))?;
+    if parent.deleted {
+        return Err(BoardError::Forbidden(
+            "Thread Deleted",
+            "This thread has been deleted and no longer accepts replies.".to_string(),
+        ));
+    }
+    if parent.locked {
+        return Err(BoardError::Forbidden("Thread Locked", "This thread is locked and no longer accepts replies.".to_string()));
+    }
+    let reply_count = count_replies(db, board, parent_id);
+    if reply_count >= MAX_REPLIES {
+        return Err(thread_full_error());
+    }
+
+    // Replies racing for the last free places are settled by the counter, which stops at MAX_REPLIES
+    let delete_password_hash = hash_delete_password(&delete_password)?;
+    let reply_id = match next_reply_id(db, board, parent_id) {
+        Ok(Some(reply_id)) => reply_id,
+        Ok(None) => return Err(thread_full_error()),
+        Err(e) => {
+            error!("Failed to allocate a reply ID for thread {}: {}", parent_id, e);
+            return Err(BoardError::Internal("Failed to post reply".to_string()));
+        }
+    };
+    let (name, tripcode) = parse_name_and_tripcode(&poster_name);
+    let spoiler = spoiler && matches!(media_type, Some(MediaType::Image)); // Only images can be spoilered
+    let reply = Reply {
+        id: reply_id,
+        message,
+        created_at: Utc::now().timestamp(),
+        name,
+        tripcode,
+        media_url,
+        media_type,
+        thumb_url,
+        spoiler,
+        media_info,
+        poster_id: compute_poster_id(db, &ip, board, parent_id),
+        country,
+        delete_password_hash,
+        deleted: false,
+    };
+
+    let value = serde_json::to_vec(&reply).expect("Failed to serialize reply");
+    db.insert(reply_key(board, parent_id, reply_id), value).map_err(|e| {
+        error!("Failed to insert reply into sled db: {}", e);
+        BoardError::Internal("Failed to post reply".to_string())
+    })?;
+
+    // Update thread's last_updated timestamp unless it has hit the bump limit or the reply is saged
+    if reply_count < BUMP_LIMIT && !sage {
+        let bumped = update_thread(db, board, parent_id, |thread| {
+            thread.last_updated = Utc::now().timestamp();
+            thread.inactive_lock = false;
+        });
+        if let Err(e) = bumped {
+            error!("Failed to bump thread {}: {}", parent_id, e);
+        }
+    }
+
+    Ok((board, parent, reply))
+}
+
+// Helper function to read a multipart text field into a string.
+// The bytes are decoded only once the whole field has arrived, as a character can be split across chunks.
+async fn read_text_field(field: &mut Field) -> Result<String, Error> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = field.next().await {
+        bytes.extend_from_slice(&chunk?);
+    }
+    String::from_utf8(bytes).map_err(|_| BoardError::BadRequest("Form fields must be valid UTF-8 text".to_string()).into())
+}
+
+// Helper function to store an uploaded media field on disk.
+// Returns the URL to display for the post along with its media type,
+// or None for an empty file, which is treated as if no media was attached.
+async fn save_media_upload(
+    db: &Db,
+    paths: &DataPaths,
+    config: &BoardConfig,
+    field: &mut Field,
+    filename: &str,
+) -> Result<Option<SavedMedia>, Error> {
+    let thumb_max_dim = config.thumb_max_dim;
+
+    // Determine the MIME type
+    let mime_type = mime_guess::from_path(filename).first_or_octet_stream();
+
+    match mime_type.type_() {
+        mime::IMAGE => {
+            // Image subtypes allowed by ALLOWED_IMAGE_FORMATS
+            if !is_image_format_allowed(config, mime_type.subtype().as_ref()) {
+                return Err(BoardError::BadRequest("Unsupported image format".to_string()).into());
+            }
+
+            // Generate a unique filename, the extension is chosen once the content is known
+            let unique_id = Uuid::new_v4().to_string();
+            let upload_path = format!("{}{}.upload", paths.image_uploads, unique_id);
+
+            // Save the image file asynchronously
+            if save_field_to_file(field, &upload_path, MAX_IMAGE_UPLOAD_BYTES, "Image").await? == 0 {
+                return Ok(None);
+            }
+
+            // Name the file after the format its bytes actually are, not the uploaded filename,
+            // so it is never served with the wrong content type
+            let subtype = match detect_image_format(&upload_path) {
+                Some(image::ImageFormat::Jpeg) => "jpeg",
+                Some(image::ImageFormat::Png) => "png",
+                Some(image::ImageFormat::Gif) => "gif",
+                Some(image::ImageFormat::WebP) => "webp",
+                Some(image::ImageFormat::Bmp) => "bmp",
+                _ => {
+                    std::fs::remove_file(&upload_path)?;
+                    return Err(BoardError::BadRequest("Invalid image file".to_string()).into());
+                }
+            };
+            // A file renamed to pass the extension check is held to the same list
+            if !is_image_format_allowed(config, subtype) {
+                std::fs::remove_file(&upload_path)?;
+                return Err(BoardError::BadRequest("Unsupported image format".to_string()).into());
+            }
+            // Read the dimensions from the header and turn away decompression bombs before decoding
+            match read_image_dimensions(&upload_path) {
+                Some((width, height)) if u64::from(width) * u64::from(height) <= MAX_IMAGE_PIXELS => {}
+                Some(_) => {
+                    std::fs::remove_file(&upload_path)?;
+                    return Err(BoardError::BadRequest("Image dimensions are too large".to_string()).into());
+                }
+                None => {
+                    std::fs::remove_file(&upload_path)?;
+                    return Err(BoardError::BadRequest("Invalid image file".to_string()).into());
+                }
+            }
+
+            // Animated images are served as uploaded, since thumbnailing keeps only the first frame
+            let is_animated = match subtype {
+                "gif" => true,
+                "webp" => is_animated_webp(&read_file_header(&upload_path, 21)),
+                _ => false,
+            };
+            let sanitized_filename = format!("{}.{}", unique_id, subtype);
+            let filepath = format!("{}{}", paths.image_uploads, sanitized_filename);
+            std::fs::rename(&upload_path, &filepath)?;
+            let filepath_clone = filepath.clone(); // Clone the filepath
+
+            // Validate the image content using the cloned filepath
+            if image::open(&filepath_clone).is_err() {
+                std::fs::remove_file(&filepath_clone)?;
+                return Err(BoardError::BadRequest("Invalid image file".to_string()).into());
+            }
+
+            // Remove EXIF data (GPS position, camera details) before the file is served
+            let strip_path = filepath_clone.clone();
+            if let Err(e) = web::block(move || strip_image_metadata(&strip_path, subtype)).await? {
+                error!("Failed to strip metadata from {}: {}", filepath_clone, e);
+                std::fs::remove_file(&filepath_clone).ok();
+                return Err(BoardError::BadRequest("Invalid image file".to_string()).into());
+            }
+
+            // Reuse an identical earlier upload instead of storing another copy
+            let hash = hash_upload(&filepath_clone).await?;
+            if let Some(existing) = find_duplicate_upload(db, paths, &hash) {
+                std::fs::remove_file(&filepath_clone).ok();
+                return Ok(Some(existing));
+            }
+
+            // Record what is shown beneath the image
+            let info = image::image_dimensions(&filepath_clone)
+                .ok()
+                .zip(std::fs::metadata(&filepath_clone).ok())
+                .map(|((width, height), metadata)| MediaInfo {
+                    width,
+                    height,
+                    file_size: metadata.len(),
+                });
+
+            // Decoding an animation yields its first frame, so every image gets a static thumbnail
+            let thumb_source = filepath_clone.clone();
+            let thumb_name = sanitized_filename.clone();
+            let thumb_dir = paths.image_thumbs.clone();
+            let thumb_filename = web::block(move || generate_image_thumbnail(&thumb_source, &thumb_name, &thumb_dir, thumb_max_dim)).await?;
+
+            let saved = match thumb_filename {
+                // GIFs and animated WEBPs keep linking to the full animation, with the still frame as a board preview
+                Some(thumb_filename) if is_animated => SavedMedia {
+                    url: format!("/uploads/images/{}", sanitized_filename),
+                    media_type: MediaType::Image,
+                    thumb_url: Some(format!("/thumbs/images/{}", thumb_filename)),
+                    info,
+                },
+                Some(thumb_filename) => SavedMedia {
+                    url: format!("/thumbs/images/{}", thumb_filename),
+                    media_type: MediaType::Image,
+                    thumb_url: None,
+                    info,
+                },
+                // If thumbnail creation failed, use the original image
+                None => SavedMedia {
+                    url: format!("/uploads/images/{}", sanitized_filename),
+                    media_type: MediaType::Image,
+                    thumb_url: None,
+                    info,
+                },
+            };
+
+            record_upload_hash(db, &hash, &saved);
+            Ok(Some(saved))
+        }
+        mime::VIDEO => {
+            // Supported video subtypes
+            if !matches!(mime_type.subtype().as_ref(), "mp4" | "webm") {
+                return Err(BoardError::BadRequest("Unsupported video format".to_string()).into());
+            }
+
+            // Generate a unique filename, the extension is chosen once the content is known
+            let unique_id = Uuid::new_v4().to_string();
+            let upload_path = format!("{}{}.upload", paths.video_uploads, unique_id);
+
+            // Save the video file asynchronously
+            if save_field_to_file(field, &upload_path, MAX_VIDEO_UPLOAD_BYTES, "Video").await? == 0 {
+                return Ok(None);
+            }
+
+            // Name the file after its container signature so renamed non-video files are rejected
+            let extension = if is_valid_mp4(&upload_path) {
+                "mp4"
+            } else if is_valid_webm(&upload_path) {
+                "webm"
+            } else {
+                std::fs::remove_file(&upload_path)?;
+                return Err(BoardError::BadRequest("Invalid video file".to_string()).into());
+            };
+            let sanitized_filename = format!("{}.{}", unique_id, extension);
+            let filepath = format!("{}{}", paths.video_uploads, sanitized_filename);
+            std::fs::rename(&upload_path, &filepath)?;
+            let filepath_clone = filepath.clone(); // Clone the filepath
+
+            // Reuse an identical earlier upload instead of storing another copy
+            let hash = hash_upload(&filepath_clone).await?;
+            if let Some(existing) = find_duplicate_upload(db, paths, &hash) {
+                std::fs::remove_file(&filepath_clone).ok();
+                return Ok(Some(existing));
+            }
+
+            // Extract a preview frame with ffmpeg, falling back to a generic placeholder
+            let thumb_filename = format!("thumb_{}.jpg", unique_id);
+            let thumb_path = format!("{}{}", paths.image_thumbs, thumb_filename);
+            let thumb_url = if web::block(move || generate_video_thumbnail(&filepath_clone, &thumb_path, thumb_max_dim)).await? {
+                format!("/thumbs/images/{}", thumb_filename)
+            } else {
+                VIDEO_PLACEHOLDER_THUMB.to_string()
+            };
+
+            let saved = SavedMedia {
+                url: format!("/uploads/videos/{}", sanitized_filename),
+                media_type: MediaType::Video,
+                thumb_url: Some(thumb_url),
+                info: None,
+            };
+
+            record_upload_hash(db, &hash, &saved);
+            Ok(Some(saved))
+        }
+        mime::AUDIO => {
+            // Supported audio subtypes
+            if !matches!(mime_type.subtype().as_ref(), "mpeg" | "ogg") {
+                return Err(BoardError::BadRequest("Unsupported audio format".to_string()).into());
+            }
+
+            // Generate a unique filename, keeping the uploaded extension (mp3 rather than mpeg)
+            let unique_id = Uuid::new_v4().to_string();
+            let extension = if mime_type.subtype().as_ref() == "mpeg" { "mp3" } else { "ogg" };
+            let sanitized_filename = format!("{}.{}", unique_id, extension);
+            let filepath = format!("{}{}", paths.audio_uploads, sanitized_filename);
+
+            // Save the audio file asynchronously
+            if save_field_to_file(field, &filepath, MAX_AUDIO_UPLOAD_BYTES, "Audio").await? == 0 {
+                return Ok(None);
+            }
+
+            // Make sure the content matches the declared type so renamed non-audio files are rejected
+            let header = read_file_header(&filepath, 4);
+            let valid = if extension == "mp3" { is_mp3_header(&header) } else { header == b"OggS" };
+            if !valid {
+                std::fs::remove_file(&filepath)?;
+                return Err(BoardError::BadRequest("Invalid audio file".to_string()).into());
+            }
+
+            // Reuse an identical earlier upload instead of storing another copy
+            let hash = hash_upload(&filepath).await?;
+            if let Some(existing) = find_duplicate_upload(db, paths, &hash) {
+                std::fs::remove_file(&filepath).ok();
+                return Ok(Some(existing));
+            }
+
+            let saved = SavedMedia {
+                url: format!("/uploads/audio/{}", sanitized_filename),
+                media_type: MediaType::Audio,
+                thumb_url: None,
+                info: None,
+            };
+
+            record_upload_hash(db, &hash, &saved);
+            Ok(Some(saved))
+        }
+        _ => Err(BoardError::BadRequest("Unsupported media type".to_string()).into()),
+    }
+}
+
+// Handler to delete a thread on the main board, leaving a placeholder until the next purge
+async fn delete_thread(
+    _admin: Admin,
+    db: web::Data<Arc<Db>>,
+    path: web::Path<(i32,)>,
+) -> Result<HttpResponse, BoardError> {
+    delete_thread_on_board(&db, MAIN_BOARD, path.into_inner().0)
+}
+
+// Handler to delete a thread on one of the extra boards
+async fn board_delete_thread(
+    _admin: Admin,
+    db: web::Data<Arc<Db>>,
+    path: web::Path<(String, i32)>,
+) -> Result<HttpResponse, BoardError> {
+    let (board, thread_id) = path.into_inner();
+    match validate_board(&board) {
+        Some(board) if board != MAIN_BOARD => delete_thread_on_board(&db, board, thread_id),
+        _ => Err(board_not_found_error()),
+    }
+}
+
+// Helper function to delete a thread for an admin and send them back to its board
+fn delete_thread_on_board(db: &Db, board: &str, thread_id: i32) -> Result<HttpResponse, BoardError> {
+    match soft_delete_post(db, board, thread_id, 0) {
+        Ok(true) => Ok(HttpResponse::SeeOther()
+            .append_header(("Location", board_path(board)))
+            .finish()),
+        Ok(false) => Err(thread_not_found_error()),
+        Err(e) => {
+            error!("Failed to delete thread {} from sled db: {}", thread_id, e);
+            Err(BoardError::Internal("Failed to delete thread".to_string()))
+        }
+    }
+}
+
+// Function to turn a thread (reply 0) or reply into a placeholder. The record and its media stay
+// until an admin purges them, so its ID is never reused and an admin can restore a mistaken deletion.
+// Its report is closed either way. Returns false when the post does not exist.
+fn soft_delete_post(db: &Db, board: &str, thread_id: i32, reply_id: i32) -> sled::Result<bool> {
+    let found = set_post_deleted(db, board, thread_id, reply_id, true)?;
+    db.remove(report_key(board, thread_id, reply_id))?;
+    Ok(found)
+}
+
+// Function to set or clear the deleted flag of a thread (reply 0) or reply.
+// Returns false when the post does not exist.
+fn set_post_deleted(db: &Db, board: &str, thread_id: i32, reply_id: i32, deleted: bool) -> sled::Result<bool> {
+    let key = if reply_id == 0 {
+        thread_key(board, thread_id)
+    } else {
+        reply_key(board, thread_id, reply_id)
+    };
+
+    // Threads and replies store the flag under the same field name
+    let previous = db.fetch_and_update(key, |old| {
+        let old = old?;
+        let mut post: serde_json::Value = match serde_json::from_slice(old) {
+            Ok(post) => post,
+            Err(_) => return Some(old.to_vec()),
+        };
+        if let Some(fields) = post.as_object_mut() {
+            fields.insert("deleted".to_string(), serde_json::Value::Bool(deleted));
+        }
+        Some(serde_json::to_vec(&post).unwrap_or_else(|_| old.to_vec()))
+    })?;
+    Ok(previous.is_some())
+}
+
+// Handler to bring back a deleted post that has not been purged yet
+async fn restore_post(
+    _admin: Admin,
+    db: web::Data<Arc<Db>>,
+    form: web::Form<ReportTarget>,
+) -> Result<HttpResponse, BoardError> {
+    let board = validate_board(form.board.trim()).ok_or_else(board_not_found_error)?;
+
+    match set_post_deleted(&db, board, form.thread_id, form.reply_id, false) {
+        Ok(true) => {
+            info!("Restored post {}/{} on board {:?}", form.thread_id, form.reply_id, board);
+            Ok(HttpResponse::SeeOther()
+                .append_header(("Location", thread_path(board, form.thread_id)))
+                .finish())
+        }
+        Ok(false) => Err(BoardError::NotFound("Post Not Found", "The post does not exist or has already been purged.".to_string())),
+        Err(e) => {
+            error!("Failed to restore post {}/{}: {}", form.thread_id, form.reply_id, e);
+            Err(BoardError::Internal("Failed to restore post".to_string()))
+        }
+    }
+}
+
+// Function to permanently remove every deleted thread and reply along with media no other post uses.
+// Returns the number of posts purged; a thread's replies go with it and are not counted.
+fn purge_deleted_posts(db: &Db, paths: &DataPaths) -> sled::Result<usize> {
+    let mut purged = 0;
+    for board in all_boards() {
+        for thread in get_all_threads_for_board(db, board) {
+            let thread_id = thread.id;
+            if thread.deleted {
+                delete_thread_cascade(db, paths, board, thread)?;
+                purged += 1;
+                continue;
+            }
+            for reply in get_replies(db, board, thread_id).iter().filter(|reply| reply.deleted) {
+                if delete_reply_cascade(db, paths, board, thread_id, reply.id)? {
+                    purged += 1;
+                }
+            }
+        }
+    }
+    Ok(purged)
+}
+
+// Handler to purge deleted posts for good
+async fn purge_deleted(
+    _admin: Admin,
+    db: web::Data<Arc<Db>>,
+    paths: web::Data<DataPaths>,
+) -> Result<HttpResponse, BoardError> {
+    let db = db.get_ref().clone();
+    let paths = paths.get_ref().clone();
+    match web::block(move || purge_deleted_posts(&db, &paths)).await? {
+        Ok(purged) => {
+            info!("Purged {} deleted posts", purged);
+            Ok(HttpResponse::SeeOther()
+                .append_header(("Location", "/admin"))
+                .finish())
+        }
+        Err(e) => {
+            error!("Failed to purge deleted posts: {}", e);
+            Err(BoardError::Internal("Failed to purge deleted posts".to_string()))
+        }
+    }
+}
+
+// Handler to switch read-only mode on or off
+async fn toggle_read_only(
+    _admin: Admin,
+    db: web::Data<Arc<Db>>,
+) -> Result<HttpResponse, BoardError> {
+    let read_only = !is_read_only(&db);
+    match set_read_only(&db, read_only) {
+        Ok(()) => {
+            info!("Board read-only: {}", read_only);
+            Ok(HttpResponse::SeeOther()
+                .append_header(("Location", "/admin"))
+                .finish())
+        }
+        Err(e) => {
+            error!("Failed to update the read-only flag: {}", e);
+            Err(BoardError::Internal("Failed to update read-only mode".to_string()))
+        }
+    }
+}
+
+// Function to delete a thread with all of its replies, then any media files no other post uses
+fn delete_thread_cascade(db: &Db, paths: &DataPaths, board: &str, thread: Thread) -> sled::Result<()> {
+    // Remove the thread and every one of its replies in a single batch
+    let mut batch = sled::Batch::default();
+    let mut media_urls: Vec<String> = thread.media_url.into_iter().chain(thread.thumb_url).collect();
+    batch.remove(thread_key(board, thread.id));
+    batch.remove(reply_counter_key(board, thread.id).into_bytes());
+    for (key, _) in db.scan_prefix(report_prefix(board, thread.id)).flatten() {
+        batch.remove(key);
+    }
+    for (key, value) in db.scan_prefix(reply_prefix(board, thread.id)).flatten() {
+        if let Ok(reply) = serde_json::from_slice::<Reply>(&value) {
+            media_urls.extend(reply.media_url);
+            media_urls.extend(reply.thumb_url);
+        }
+        batch.remove(key);
+    }
+    db.apply_batch(batch)?;
+
+    for url in &media_urls {
+        if !media_in_use(db, url) {
+            delete_media_files(paths, url);
+        }
+    }
+    Ok(())
+}
+
+// Function to delete a single reply and its report, then any media files no other post uses.
+// Returns false when the reply does not exist.
+fn delete_reply_cascade(db: &Db, paths: &DataPaths, board: &str, parent_id: i32, reply_id: i32) -> sled::Result<bool> {
+    let value = match db.remove(reply_key(board, parent_id, reply_id))? {
+        Some(value) => value,
+        None => return Ok(false),
+    };
+    db.remove(report_key(board, parent_id, reply_id))?;
+
+    if let Ok(reply) = serde_json::from_slice::<Reply>(&value) {
+        for url in reply.media_url.iter().chain(reply.thumb_url.iter()) {
+            if !media_in_use(db, url) {
+                delete_media_files(paths, url);
+            }
+        }
+    }
+    Ok(true)
+}
+
+// Function to remove the files of an upload whose post was rejected, unless another post shows them
+fn discard_upload(db: &Db, paths: &DataPaths, media_url: &Option<String>, thumb_url: &Option<String>) {
+    for url in media_url.iter().chain(thumb_url.iter()) {
+        if !media_in_use(db, url) {
+            delete_media_files(paths, url);
+        }
+    }
+}
+
+// Function to delete the least recently bumped threads of every board past the thread cap.
+// Stickied threads are never pruned and do not count towards the cap.
+fn prune_threads(db: &Db, paths: &DataPaths, max_threads: usize) {
+    for board in all_boards() {
+        let mut threads: Vec<Thread> = get_all_threads_for_board(db, board)
+            .into_iter()
+            .filter(|thread| !thread.sticky)
+            .collect();
+        if threads.len() <= max_threads {
+            continue;
+        }
+
+        sort_threads_by_bump(&mut threads);
+        for thread in threads.split_off(max_threads) {
+            let thread_id = thread.id;
+            match delete_thread_cascade(db, paths, board, thread) {
+                Ok(()) => info!("Pruned thread {} from board {:?}", thread_id, board),
+                Err(e) => error!("Failed to prune thread {} from board {:?}: {}", thread_id, board, e),
+            }
+        }
+    }
+}
+
+// Function to lock every thread on every board whose last bump is older than the given number of days.
+// Sticky threads are left open, and so are threads an admin unlocked after the job locked them,
+// until they are bumped and go quiet again. Returns how many threads were locked.
+fn lock_inactive_threads(db: &Db, max_age_days: i32) -> usize {
+    let cutoff = Utc::now().timestamp() - i64::from(max_age_days) * 86_400;
+    let mut locked = 0;
+    for board in all_boards() {
+        let inactive = get_all_threads_for_board(db, board)
+            .into_iter()
+            .filter(|thread| !thread.sticky && !thread.locked && !thread.inactive_lock && !thread.deleted)
+            .filter(|thread| thread.last_updated < cutoff);
+        for thread in inactive {
+            let result = update_thread(db, board, thread.id, |thread| {
+                thread.locked = true;
+                thread.inactive_lock = true;
+            });
+            match result {
+                Ok(Some(_)) => locked += 1,
+                Ok(None) => {}
+                Err(e) => error!("Failed to lock inactive thread {} on board {:?}: {}", thread.id, board, e),
+            }
+        }
+    }
+    locked
+}
+
+// Handler to pin a thread to the top of the main board, or unpin it
+async fn toggle_sticky(
+    _admin: Admin,
+    db: web::Data<Arc<Db>>,
+    path: web::Path<(i32,)>,
+) -> Result<HttpResponse, BoardError> {
+    toggle_sticky_on_board(&db, MAIN_BOARD, path.into_inner().0)
+}
+
+// Handler to pin a thread to the top of one of the extra boards, or unpin it
+async fn board_toggle_sticky(
+    _admin: Admin,
+    db: web::Data<Arc<Db>>,
+    path: web::Path<(String, i32)>,
+) -> Result<HttpResponse, BoardError> {
+    let (board, thread_id) = path.into_inner();
+    match validate_board(&board) {
+        Some(board) if board != MAIN_BOARD => toggle_sticky_on_board(&db, board, thread_id),
+        _ => Err(board_not_found_error()),
+    }
+}
+
+// Helper function to flip a thread's sticky flag and send the admin back to its board
+fn toggle_sticky_on_board(db: &Db, board: &str, thread_id: i32) -> Result<HttpResponse, BoardError> {
+    match update_thread(db, board, thread_id, |thread| thread.sticky = !thread.sticky) {
+        Ok(Some(thread)) => {
+            info!("Thread {} on board {:?} sticky: {}", thread_id, board, thread.sticky);
+            Ok(HttpResponse::SeeOther()
+                .append_header(("Location", board_path(board)))
+                .finish())
+        }
+        Ok(None) => Err(thread_not_found_error()),
+        Err(e) => {
+            error!("Failed to update thread {}: {}", thread_id, e);
+            Err(BoardError::Internal("Failed to update thread".to_string()))
+        }
+    }
+}
+
+// Handler to lock a thread on the main board against new replies, or unlock it
+async fn toggle_lock(
+    _admin: Admin,
+    db: web::Data<Arc<Db>>,
+    path: web::Path<(i32,)>,
+) -> Result<HttpResponse, BoardError> {
+    toggle_lock_on_board(&db, MAIN_BOARD, path.into_inner().0)
+}
+
+// Handler to lock a thread on one of the extra boards against new replies, or unlock it
+async fn board_toggle_lock(
+    _admin: Admin,
+    db: web::Data<Arc<Db>>,
+    path: web::Path<(String, i32)>,
+) -> Result<HttpResponse, BoardError> {
+    let (board, thread_id) = path.into_inner();
+    match validate_board(&board) {
+        Some(board) if board != MAIN_BOARD => toggle_lock_on_board(&db, board, thread_id),
+        _ => Err(board_not_found_error()),
+    }
+}
+
+// Helper function to flip a thread's lock and send the admin back to the thread
+fn toggle_lock_on_board(db: &Db, board: &str, thread_id: i32) -> Result<HttpResponse, BoardError> {
+    match update_thread(db, board, thread_id, |thread| thread.locked = !thread.locked) {
+        Ok(Some(thread)) => {
+            info!("Thread {} on board {:?} locked: {}", thread_id, board, thread.locked);
+            Ok(HttpResponse::SeeOther()
+                .append_header(("Location", thread_path(board, thread_id)))
+                .finish())
+        }
+        Ok(None) => Err(thread_not_found_error()),
+        Err(e) => {
+            error!("Failed to update thread {}: {}", thread_id, e);
+            Err(BoardError::Internal("Failed to update thread".to_string()))
+        }
+    }
+}
+
+// Handler to delete a single reply from a thread on the main board, leaving a placeholder until the next purge
+async fn delete_reply(
+    _admin: Admin,
+    db: web::Data<Arc<Db>>,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, BoardError> {
+    let (parent_id, reply_id) = path.into_inner();
+    delete_reply_on_board(&db, MAIN_BOARD, parent_id, reply_id)
+}
+
+// Handler to delete a single reply from a thread on one of the extra boards
+async fn board_delete_reply(
+    _admin: Admin,
+    db: web::Data<Arc<Db>>,
+    path: web::Path<(String, i32, i32)>,
+) -> Result<HttpResponse, BoardError> {
+    let (board, parent_id, reply_id) = path.into_inner();
+    match validate_board(&board) {
+        Some(board) if board != MAIN_BOARD => delete_reply_on_board(&db, board, parent_id, reply_id),
+        _ => Err(board_not_found_error()),
+    }
+}
+
+// Helper function to delete a reply for an admin and send them back to its thread
+fn delete_reply_on_board(db: &Db, board: &str, parent_id: i32, reply_id: i32) -> Result<HttpResponse, BoardError> {
+    if !db.contains_key(thread_key(board, parent_id)).unwrap_or(false) {
+        return Err(thread_not_found_error());
+    }
+
+    match soft_delete_post(db, board, parent_id, reply_id) {
+        Ok(true) => Ok(HttpResponse::SeeOther()
+            .append_header(("Location", thread_path(board, parent_id)))
+            .finish()),
+        Ok(false) => Err(BoardError::NotFound("Reply Not Found", "The requested reply does not exist.".to_string())),
+        Err(_) => {
+            error!("Failed to delete reply {} of thread {} from sled db", reply_id, parent_id);
+            Err(BoardError::Internal("Failed to delete reply".to_string()))
+        }
+    }
+}
+
+// Handler to report a post to the admins
+async fn report_post(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    form: web::Form<ReportForm>,
+) -> Result<HttpResponse, BoardError> {
+    check_not_banned(&db, &client_ip(&req))?;
+
+    let form = form.into_inner();
+    let board = match validate_board(form.board.trim()) {
+        Some(board) => board,
+        None => return Err(board_not_found_error()),
+    };
+
+    let reason = form.reason.trim();
+    if reason.is_empty() {
+        return Err(BoardError::BadRequest("Report reason cannot be empty".to_string()));
+    }
+    if reason.chars().count() > MAX_REPORT_REASON_CHARS {
+        return Err(BoardError::BadRequest(format!(
+            "Report reason cannot be longer than {} characters",
+            MAX_REPORT_REASON_CHARS
+        )));
+    }
+
+    let post_key = if form.reply_id == 0 {
+        thread_key(board, form.thread_id)
+    } else {
+        reply_key(board, form.thread_id, form.reply_id)
+    };
+    if !db.contains_key(post_key).unwrap_or(false) {
+        return Err(BoardError::NotFound("Post Not Found", "The reported post does not exist.".to_string()));
+    }
+
+    let report = Report {
+        board: board.to_string(),
+        thread_id: form.thread_id,
+        reply_id: form.reply_id,
+        reason: reason.to_string(),
+        created_at: Utc::now().timestamp(),
+    };
+    let value = serde_json::to_vec(&report).expect("Failed to serialize report");
+
+    if db.insert(report_key(board, form.thread_id, form.reply_id), value).is_err() {
+        error!("Failed to insert report into sled db");
+        return Err(BoardError::Internal("Failed to send report".to_string()));
+    }
+
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", thread_path(board, form.thread_id)))
+        .finish())
+}
+
+// Handler for posters deleting their own post with the password they set when posting
+async fn delete_own_post(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    delete_attempts: web::Data<DeleteAttempts>,
+    form: web::Form<DeletePostForm>,
+) -> Result<HttpResponse, BoardError> {
+    let ip = client_ip(&req);
+    check_not_read_only(&db)?;
+    check_not_banned(&db, &ip)?;
+
+    let form = form.into_inner();
+    let board = match validate_board(form.board.trim()) {
+        Some(board) => board,
+        None => return Err(board_not_found_error()),
+    };
+
+    let post_key = if form.reply_id == 0 {
+        thread_key(board, form.thread_id)
+    } else {
+        reply_key(board, form.thread_id, form.reply_id)
+    };
+    let value = match db.get(post_key) {
+        Ok(Some(value)) => value,
+        _ => {
+            return Err(BoardError::NotFound("Post Not Found", "The post does not exist.".to_string()));
+        }
+    };
+
+    // Threads and replies store the hash and the deleted flag under the same field names
+    let post = serde_json::from_slice::<serde_json::Value>(&value).ok();
+    if post.as_ref().and_then(|post| post.get("deleted")?.as_bool()) == Some(true) {
+        return Err(BoardError::NotFound("Post Not Found", "The post has already been deleted.".to_string()));
+    }
+    let stored_hash = post.and_then(|post| post.get("delete_password_hash")?.as_str().map(str::to_string));
+    let stored_hash = match stored_hash {
+        Some(hash) => hash,
+        None => {
+            return Err(BoardError::Forbidden("Forbidden", "This post was made without a deletion password.".to_string()));
+        }
+    };
+
+    // Checking a password is deliberately expensive, so each client only gets a few tries
+    take_delete_attempt(&delete_attempts, &ip)?;
+    let password = form.password;
+    if !web::block(move || verify_delete_password(&password, &stored_hash)).await? {
+        return Err(BoardError::Forbidden("Forbidden", "Incorrect deletion password.".to_string()));
+    }
+
+    // Deleting the opening post closes the whole thread
+    let location = if form.reply_id == 0 {
+        board_path(board)
+    } else {
+        thread_path(board, form.thread_id)
+    };
+
+    if let Err(e) = soft_delete_post(&db, board, form.thread_id, form.reply_id) {
+        error!("Failed to delete post {}/{} from sled db: {}", form.thread_id, form.reply_id, e);
+        return Err(BoardError::Internal("Failed to delete post".to_string()));
+    }
+
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", location))
+        .finish())
+}
+
+// Helper function to hash a post's optional deletion password with Argon2.
+// Hashing is deliberately slow, so call this off the async workers.
+fn hash_delete_password(password: &str) -> Result<Option<String>, BoardError> {
+    if password.is_empty() {
+        return Ok(None);
+    }
+
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| Some(hash.to_string()))
+        .map_err(|e| {
+            error!("Failed to hash deletion password: {}", e);
+            BoardError::Internal("Failed to hash deletion password".to_string())
+        })
+}
+
+// Helper function to check a deletion password against its stored Argon2 hash.
+// The comparison inside verify_password runs in constant time.
+fn verify_delete_password(password: &str, stored_hash: &str) -> bool {
+    PasswordHash::new(stored_hash)
+        .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+        .unwrap_or(false)
+}
+
+// Handler listing the stored threads and replies that can no longer be decoded, so corruption
+// can be found and repaired instead of the posts silently vanishing from the board
+async fn admin_unreadable_records(
+    _admin: Admin,
+    db: web::Data<Arc<Db>>,
+    config: web::Data<BoardConfig>,
+) -> Result<HttpResponse, BoardError> {
+    let keys = web::block(move || {
+        all_boards()
+            .flat_map(|board| {
+                let prefix = board_key_prefix(board);
+                [(format!("{}thread_", prefix), false), (format!("{}reply_", prefix), true)]
+            })
+            .flat_map(|(prefix, is_reply)| {
+                db.scan_prefix(prefix).filter_map(move |res| match res {
+                    Ok((key, value)) => {
+                        let readable = if is_reply {
+                            serde_json::from_slice::<Reply>(&value).is_ok()
+                        } else {
+                            serde_json::from_slice::<Thread>(&value).is_ok()
+                        };
+                        (!readable).then(|| String::from_utf8_lossy(&key).into_owned())
+                    }
+                    Err(e) => Some(format!("(scan error: {})", e)),
+                })
+            })
+            .collect::<Vec<String>>()
+    })
+    .await?;
+
+    let keys_html = if keys.is_empty() {
+        "<li>All threads and replies can be read.</li>".to_string()
+    } else {
+        keys.iter().map(|key| format!("<li>{}</li>", escape_html(key))).collect::<Vec<String>>().join("\n")
+    };
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>Unreadable Records</title>
+    <link rel="stylesheet" href="/static/style.css">
+</head>
+<body>
+    {}
+    <div class="board-nav"><a href="/admin">Back to Dashboard</a></div>
+    <hr>
+
+    <p>{} unreadable records. They are left out of every page until repaired or removed.</p>
+    <ul class="board-list">
+        {}
+    </ul>
+
+    <div class="footer">
+        - Powered by Rust and Actix Web -
+    </div>
+</body>
+</html>"#,
+        render_logo(&config, "Unreadable Records"),
+        keys.len(),
+        keys_html
+    );
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+// Handler for the admin dashboard with post counts, disk usage and the newest reports
+async fn admin_dashboard(
+    _admin: Admin,
+    db: web::Data<Arc<Db>>,
+    config: web::Data<BoardConfig>,
+    paths: web::Data<DataPaths>,
+) -> Result<HttpResponse, BoardError> {
+    let mut total_threads = 0;
+    let mut total_replies = 0;
+    let mut total_deleted = 0;
+    let mut boards_html = String::new();
+    for board in all_boards() {
+        let threads = get_all_threads_for_board(&db, board);
+        let replies: Vec<Reply> = db
+            .scan_prefix(format!("{}reply_", board_key_prefix(board)))
+            .values()
+            .flatten()
+            .filter_map(|value| serde_json::from_slice(&value).ok())
+            .collect();
+        total_deleted += threads.iter().filter(|thread| thread.deleted).count();
+        total_deleted += replies.iter().filter(|reply| reply.deleted).count();
+        let (threads, replies) = (threads.len(), replies.len());
+        total_threads += threads;
+        total_replies += replies;
+        boards_html.push_str(&format!(
+            r#"<li><a href="{}">{}</a>: Threads: {}, Replies: {}</li>"#,
+            board_path(board),
+            escape_html(&if board == MAIN_BOARD { "Main Board".to_string() } else { board_title(&config, board) }),
+            threads,
+            replies
+        ));
+    }
+
+    // Walking the media folders touches every file, so keep it off the async workers
+    let dirs: Vec<String> = paths.media_dirs().iter().map(|dir| dir.to_string()).collect();
+    let sizes = web::block(move || {
+        dirs.into_iter()
+            .map(|dir| {
+                let size = directory_size(std::path::Path::new(&dir));
+                (dir, size)
+            })
+            .collect::<Vec<(String, u64)>>()
+    })
+    .await?;
+    let total_size: u64 = sizes.iter().map(|(_, size)| size).sum();
+    let disk_html: String = sizes
+        .iter()
+        .map(|(dir, size)| format!("<li>{}: {}</li>", escape_html(dir), format_file_size(*size)))
+        .collect();
+
+    let reports = get_all_reports(&db);
+    let reports_html = if reports.is_empty() {
+        "<li>No open reports.</li>".to_string()
+    } else {
+        reports.iter().take(DASHBOARD_REPORTS).map(|report| render_report(&config, report)).collect::<Vec<String>>().join("\n")
+    };
+
+    let read_only_html = if is_read_only(&db) {
+        "Posting: read-only <form action=\"/admin/read-only\" method=\"post\" class=\"inline-form\"><input type=\"submit\" value=\"Reopen Posting\"></form>"
+    } else {
+        "Posting: open <form action=\"/admin/read-only\" method=\"post\" class=\"inline-form\"><input type=\"submit\" value=\"Make Read-Only\"></form>"
+    };
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>Admin</title>
+    <link rel="stylesheet" href="/static/style.css">
+</head>
+<body>
+    {}
+    <div class="board-nav"><a href="/admin/reports">Reports ({})</a> | <a href="/admin/bans">Bans</a> | <a href="/recent">Recent Replies</a> | <a href="/admin/export">Export</a> | <a href="/admin/records">Unreadable Records</a> | <a href="/metrics">Metrics</a> | <form action="/admin/logout" method="post" class="inline-form"><input type="submit" value="Log Out"></form></div>
+    <hr>
+
+    <!-- Post Counts -->
+    <h2>Threads: {}, Replies: {}</h2>
+    <ul class="board-list">
+        {}
+    </ul>
+    <div>Deleted posts awaiting purge: {} <form action="/admin/purge" method="post" class="inline-form"><input type="submit" value="Purge Now"></form></div>
+    <div>{}</div>
+    <hr>
+
+    <!-- Disk Usage -->
+    <h2>Media: {}</h2>
+    <ul class="board-list">
+        {}
+    </ul>
+    <hr>
+
+    <!-- Newest Reports -->
+    <h2>Newest Reports</h2>
+    <ul class="board-list">
+        {}
+    </ul>
+
+    <div class="footer">
+        - Powered by Rust and Actix Web -
+    </div>
+</body>
+</html>"#,
+        render_logo(&config, "Admin"),
+        reports.len(),
+        total_threads,
+        total_replies,
+        boards_html,
+        total_deleted,
+        read_only_html,
+        format_file_size(total_size),
+        disk_html,
+        reports_html
+    );
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+// Helper function to add up the size of every file beneath a directory
+fn directory_size(dir: &std::path::Path) -> u64 {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Failed to read directory {}: {}", dir.display(), e);
+            return 0;
+        }
+    };
+
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => directory_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+// Handler for the admin page listing open reports
+async fn admin_reports(_admin: Admin, db: web::Data<Arc<Db>>, config: web::Data<BoardConfig>) -> impl Responder {
+    let reports = get_all_reports(&db);
+    let reports_html = if reports.is_empty() {
+        "<li>No open reports.</li>".to_string()
+    } else {
+        reports.iter().map(|report| render_report(&config, report)).collect::<Vec<String>>().join("\n")
+    };
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>Reports</title>
+    <link rel="stylesheet" href="/static/style.css">
+</head>
+<body>
+    {}
+    <hr>
+
+    <!-- Open Reports -->
+    <ul class="board-list">
+        {}
+    </ul>
+
+    <div class="footer">
+        - Powered by Rust and Actix Web -
+    </div>
+</body>
+</html>"#,
+        render_logo(&config, "Reports"),
+        reports_html
+    );
+
+    HttpResponse::Ok().content_type("text/html").body(html)
+}
+
+// Helper function to render an open report with its dismiss and delete actions
+fn render_report(config: &BoardConfig, report: &Report) -> String {
+    let (post_url, post_label) = if report.reply_id == 0 {
+        (thread_path(&report.board, report.thread_id), format!("Thread {}", report.thread_id))
+    } else {
+        (
+            format!("{}#reply-{}", thread_path(&report.board, report.thread_id), report.reply_id),
+            format!("Thread {}, reply {}", report.thread_id, report.reply_id),
+        )
+    };
+    let target_fields = format!(
+        r#"<input type="hidden" name="board" value="{}"><input type="hidden" name="thread_id" value="{}"><input type="hidden" name="reply_id" value="{}">"#,
+        escape_html(&report.board),
+        report.thread_id,
+        report.reply_id
+    );
+
+    format!(
+        r#"<li><a href="{}">{}</a> on {}: {} ({}) <form action="/admin/reports/dismiss" method="post" class="inline-form">{}<input type="submit" value="Dismiss"></form> <form action="/admin/reports/delete" method="post" class="inline-form">{}<input type="submit" value="Delete Post"></form></li>"#,
+        post_url,
+        post_label,
+        escape_html(&board_title(config, &report.board)),
+        escape_html(&report.reason),
+        format_timestamp(report.created_at),
+        target_fields,
+        target_fields
+    )
+}
+
+// Handler to close a report without acting on the post
+async fn dismiss_report(
+    _admin: Admin,
+    db: web::Data<Arc<Db>>,
+    form: web::Form<ReportTarget>,
+) -> Result<HttpResponse, BoardError> {
+    let board = match validate_board(form.board.trim()) {
+        Some(board) => board,
+        None => return Err(board_not_found_error()),
+    };
+
+    if db.remove(report_key(board, form.thread_id, form.reply_id)).is_err() {
+        error!("Failed to remove report from sled db");
+        return Err(BoardError::Internal("Failed to dismiss report".to_string()));
+    }
+
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", "/admin/reports"))
+        .finish())
+}
+
+// Handler to delete a reported post, which also closes its report
+async fn delete_reported_post(
+    _admin: Admin,
+    db: web::Data<Arc<Db>>,
+    form: web::Form<ReportTarget>,
+) -> Result<HttpResponse, BoardError> {
+    let board = match validate_board(form.board.trim()) {
+        Some(board) => board,
+        None => return Err(board_not_found_error()),
+    };
+
+    if let Err(e) = soft_delete_post(&db, board, form.thread_id, form.reply_id) {
+        error!("Failed to delete reported post from sled db: {}", e);
+        return Err(BoardError::Internal("Failed to delete post".to_string()));
+    }
+
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", "/admin/reports"))
+        .finish())
+}
+
+// Handler for the admin login form
+async fn admin_login_page(config: web::Data<BoardConfig>) -> impl Responder {
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>Admin Login</title>
+    <link rel="stylesheet" href="/static/style.css">
+</head>
+<body>
+    {}
+    <hr>
+
+    <!-- Login Form -->
+    <div class="postarea">
+        <form class="postform" action="/admin/login" method="post">
+            <input type="password" name="password" placeholder="Password" required aria-label="Password">
+            <input type="submit" value="Log In">
+        </form>
+    </div>
+
+    <div class="footer">
+        - Powered by Rust and Actix Web -
+    </div>
+</body>
+</html>"#,
+        render_logo(&config, "Admin Login")
+    );
+
+    HttpResponse::Ok().content_type("text/html").body(html)
+}
+
+// Handler to check the admin password and set the session cookie
+async fn admin_login(
+    config: web::Data<BoardConfig>,
+    form: web::Form<LoginForm>,
+) -> Result<HttpResponse, BoardError> {
+    let (password, session) = match (&config.admin_password, &config.admin_session) {
+        (Some(password), Some(session)) => (password, session),
+        _ => {
+            return Err(BoardError::Forbidden("Forbidden", "Admin login is disabled because ADMIN_PASSWORD is not set.".to_string()));
+        }
+    };
+
+    if !constant_time_eq(form.password.as_bytes(), password.as_bytes()) {
+        warn!("Failed admin login attempt");
+        return Err(BoardError::Unauthorized("Incorrect password.".to_string()));
+    }
+
+    let cookie = Cookie::build(ADMIN_COOKIE, session.clone())
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .finish();
+    Ok(HttpResponse::SeeOther()
+        .cookie(cookie)
+        .append_header(("Location", "/admin"))
+        .finish())
+}
+
+// Handler to log out by clearing the session cookie
+async fn admin_logout() -> impl Responder {
+    let mut cookie = Cookie::build(ADMIN_COOKIE, "").path("/").finish();
+    cookie.make_removal();
+    HttpResponse::SeeOther()
+        .cookie(cookie)
+        .append_header(("Location", "/"))
+        .finish()
+}
+
+// Handler to download every board as a JSON backup.
+// Threads are read and serialized one at a time as the response is streamed.
+async fn admin_export(_admin: Admin, db: web::Data<Arc<Db>>) -> impl Responder {
+    let db = db.get_ref().clone();
+    let threads_db = db.clone();
+    let threads = all_boards()
+        .flat_map(move |board| {
+            get_all_threads_for_board(&threads_db, board)
+                .into_iter()
+                .map(move |thread| (board, thread))
+        })
+        .enumerate()
+        .map(move |(index, (board, thread))| {
+            let dumped = DumpedThread {
+                board: board.to_string(),
+                replies: get_replies(&db, board, thread.id),
+                thread,
+            };
+            let json = serde_json::to_string(&dumped).expect("Failed to serialize thread");
+            let separator = if index == 0 { "" } else { "," };
+            Ok::<_, Error>(web::Bytes::from(format!("{}{}", separator, json)))
+        });
+
+    let body = futures_util::stream::iter(std::iter::once(Ok(web::Bytes::from_static(b"{\"threads\":["))))
+        .chain(futures_util::stream::iter(threads))
+        .chain(futures_util::stream::iter(std::iter::once(Ok(web::Bytes::from_static(b"]}")))));
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .append_header((header::CONTENT_DISPOSITION, r#"attachment; filename="board-export.json""#))
+        .streaming(body)
+}
+
+// Handler to restore threads and replies from an /admin/export backup.
+// Threads that already exist are left alone, and ID counters only ever move up.
+async fn admin_import(_admin: Admin, db: web::Data<Arc<Db>>, dump: web::Json<BoardDump>) -> impl Responder {
+    let dump = dump.into_inner();
+    if let Err(message) = validate_board_dump(&dump) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(json_error(&message));
+    }
+
+    let db = db.get_ref().clone();
+    match web::block(move || import_board_dump(&db, dump)).await {
+        Ok(Ok((imported, skipped))) => {
+            info!("Imported {} threads, skipped {} existing ones", imported, skipped);
+            HttpResponse::Ok()
+                .content_type("application/json")
+                .body(serde_json::json!({ "imported": imported, "skipped": skipped }).to_string())
+        }
+        Ok(Err(e)) => {
+            error!("Failed to import board dump: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .body(json_error("Failed to import board dump"))
+        }
+        Err(e) => {
+            error!("Board import task failed: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .body(json_error("Failed to import board dump"))
+        }
+    }
+}
+
+// Helper function to check a board dump before anything is written.
+// Returns a message describing the first problem found.
+fn validate_board_dump(dump: &BoardDump) -> Result<(), String> {
+    let mut seen_threads = std::collections::HashSet::new();
+    for dumped in &dump.threads {
+        if validate_board(&dumped.board).is_none() {
+            return Err(format!("Unknown board {:?}", dumped.board));
+        }
+        if dumped.thread.id < 1 {
+            return Err(format!("Invalid thread ID {} on board {:?}", dumped.thread.id, dumped.board));
+        }
+        if !seen_threads.insert((dumped.board.as_str(), dumped.thread.id)) {
+            return Err(format!("Thread {} appears twice on board {:?}", dumped.thread.id, dumped.board));
+        }
+
+        let mut seen_replies = std::collections::HashSet::new();
+        for reply in &dumped.replies {
+            if reply.id < 1 || !seen_replies.insert(reply.id) {
+                return Err(format!("Invalid or repeated reply ID {} in thread {}", reply.id, dumped.thread.id));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Function to write a validated board dump into the Sled database.
+// Returns how many threads were imported and how many were skipped because they already exist.
+fn import_board_dump(db: &Db, dump: BoardDump) -> sled::Result<(usize, usize)> {
+    let (mut imported, mut skipped) = (0, 0);
+
+    for dumped in dump.threads {
+        let board = match validate_board(&dumped.board) {
+            Some(board) => board,
+            None => continue,
+        };
+        let thread_id = dumped.thread.id;
+        if db.contains_key(thread_key(board, thread_id))? {
+            skipped += 1;
+            continue;
+        }
+
+        // Each thread arrives together with its replies or not at all
+        let mut batch = sled::Batch::default();
+        let highest_reply_id = dumped.replies.iter().map(|reply| reply.id).max().unwrap_or(0);
+        for reply in &dumped.replies {
+            let value = serde_json::to_vec(reply).expect("Failed to serialize reply");
+            batch.insert(reply_key(board, thread_id, reply.id), value);
+        }
+        let value = serde_json::to_vec(&dumped.thread).expect("Failed to serialize thread");
+        batch.insert(thread_key(board, thread_id), value);
+        db.apply_batch(batch)?;
+
+        // New posts must never reuse an imported ID
+        raise_id_counter(db, &format!("{}next_thread_id", board_key_prefix(board)), thread_id)?;
+        raise_id_counter(db, &reply_counter_key(board, thread_id), highest_reply_id)?;
+        imported += 1;
+    }
+
+    db.flush()?;
+    Ok((imported, skipped))
+}
+
+// Function to make sure an ID counter is at least the given value.
+// Missing counters are left alone since next_id seeds them from the highest stored ID.
+fn raise_id_counter(db: &Db, counter_key: &str, at_least: i32) -> sled::Result<()> {
+    let decode = |bytes: &[u8]| <[u8; 4]>::try_from(bytes).ok().map(i32::from_be_bytes);
+    db.update_and_fetch(counter_key, |old| {
+        old.map(|bytes| match decode(bytes) {
+            Some(current) => current.max(at_least).to_be_bytes().to_vec(),
+            None => bytes.to_vec(),
+        })
+    })?;
+    Ok(())
+}
+
+// Handler for the admin page listing active bans
+async fn admin_bans(_admin: Admin, db: web::Data<Arc<Db>>, config: web::Data<BoardConfig>) -> impl Responder {
+    let bans_html = get_active_bans(&db)
+        .iter()
+        .map(|ban| {
+            format!(
+                r#"<li>{} until {}{} <form action="/admin/unban" method="post" class="inline-form"><input type="hidden" name="ip" value="{}"><input type="submit" value="Unban"></form></li>"#,
+                escape_html(&ban.ip),
+                format_timestamp(ban.expires_at),
+                if ban.reason.is_empty() { String::new() } else { format!(" ({})", escape_html(&ban.reason)) },
+                escape_html(&ban.ip)
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>Bans</title>
+    <link rel="stylesheet" href="/static/style.css">
+</head>
+<body>
+    {}
+    <hr>
+
+    <!-- Ban Form -->
+    <div class="postarea">
+        <form class="postform" action="/admin/ban" method="post">
+            <input type="text" name="ip" placeholder="IP address" required aria-label="IP address">
+            <input type="text" name="reason" maxlength="200" placeholder="Reason" aria-label="Reason">
+            <input type="text" name="hours" value="24" required aria-label="Hours">
+            <input type="submit" value="Ban">
+        </form>
+    </div>
+    <hr>
+
+    <!-- Active Bans -->
+    <ul class="board-list">
+        {}
+    </ul>
+
+    <div class="footer">
+        - Powered by Rust and Actix Web -
+    </div>
+</body>
+</html>"#,
+        render_logo(&config, "Bans"),
+        bans_html
+    );
+
+    HttpResponse::Ok().content_type("text/html").body(html)
+}
+
+// Handler to ban a client IP from posting for a number of hours
+async fn ban_ip(
+    _admin: Admin,
+    db: web::Data<Arc<Db>>,
+    form: web::Form<BanForm>,
+) -> Result<HttpResponse, BoardError> {
+    let form = form.into_inner();
+    let ip = parse_ban_ip(&form.ip)?;
+    if form.hours <= 0 {
+        return Err(BoardError::BadRequest("Ban length must be at least one hour".to_string()));
+    }
+
+    let ban = Ban {
+        ip,
+        reason: form.reason.trim().to_string(),
+        expires_at: Utc::now().timestamp().saturating_add(form.hours.saturating_mul(3600)),
+    };
+    let value = serde_json::to_vec(&ban).expect("Failed to serialize ban");
+
+    if db.insert(ban_key(&ban.ip), value).is_err() {
+        error!("Failed to insert ban for {} into sled db", ban.ip);
+        return Err(BoardError::Internal("Failed to ban IP".to_string()));
+    }
+
+    info!("Banned {} until {}", ban.ip, format_timestamp(ban.expires_at));
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", "/admin/bans"))
+        .finish())
+}
+
+// Handler to lift a ban before it expires
+async fn unban_ip(
+    _admin: Admin,
+    db: web::Data<Arc<Db>>,
+    form: web::Form<UnbanForm>,
+) -> Result<HttpResponse, BoardError> {
+    let ip = parse_ban_ip(&form.ip)?;
+    match db.remove(ban_key(&ip)) {
+        Ok(Some(_)) => {
+            info!("Unbanned {}", ip);
+            Ok(HttpResponse::SeeOther()
+                .append_header(("Location", "/admin/bans"))
+                .finish())
+        }
+        Ok(None) => Err(BoardError::NotFound("Ban Not Found", format!("{} is not banned.", ip))),
+        Err(e) => {
+            error!("Failed to remove ban for {} from sled db: {}", ip, e);
+            Err(BoardError::Internal("Failed to unban IP".to_string()))
+        }
+    }
+}
+
+// Helper function to read an IP address typed into the ban forms in the canonical form bans are stored under
+fn parse_ban_ip(input: &str) -> Result<String, BoardError> {
+    input
+        .trim()
+        .parse::<std::net::IpAddr>()
+        .map(|ip| ip.to_string())
+        .map_err(|_| BoardError::BadRequest("Invalid IP address".to_string()))
+}
+
+// Helper function to build the Sled key holding a ban
+fn ban_key(ip: &str) -> Vec<u8> {
+    format!("ban_{}", ip).into_bytes()
+}
+
+// Function to look up the ban on an IP, removing it once it has expired
+fn active_ban(db: &Db, ip: &str) -> Option<Ban> {
+    let key = ban_key(ip);
+    let ban: Ban = db.get(&key).ok().flatten().and_then(|value| {
+        serde_json::from_slice(&value).ok()
+    })?;
+
+    if ban.expires_at <= Utc::now().timestamp() {
+        db.remove(&key).ok();
+        return None;
+    }
+    Some(ban)
+}
+
+// Function to retrieve every unexpired ban, soonest to expire first
+fn get_active_bans(db: &Db) -> Vec<Ban> {
+    let now = Utc::now().timestamp();
+    let mut bans: Vec<Ban> = db
+        .scan_prefix(b"ban_")
+        .filter_map(|item| item.ok())
+        .filter_map(|(_, value)| serde_json::from_slice::<Ban>(&value).ok())
+        .filter(|ban| ban.expires_at > now)
+        .collect();
+    bans.sort_by_key(|ban| ban.expires_at);
+    bans
+}
+
+// Helper function to compute the SHA-256 of an uploaded file as a hex string
+async fn hash_upload(filepath: &str) -> Result<String, Error> {
+    let path = filepath.to_string();
+    let bytes = web::block(move || std::fs::read(path)).await??;
+    Ok(format!("{:x}", Sha256::digest(&bytes)))
+}
+
+// Function to find an earlier upload with the same content hash.
+// Entries whose files have since been deleted are ignored.
+fn find_duplicate_upload(db: &Db, paths: &DataPaths, hash: &str) -> Option<SavedMedia> {
+    let key = format!("filehash_{}", hash).into_bytes();
+    let saved: SavedMedia = db.get(&key).ok().flatten().and_then(|value| {
+        serde_json::from_slice(&value).ok()
+    })?;
+
+    let files_exist = std::iter::once(&saved.url)
+        .chain(saved.thumb_url.iter())
+        .filter_map(|url| media_file_path(paths, url))
+        .all(|path| std::path::Path::new(&path).exists());
+    files_exist.then_some(saved)
+}
+
+// Function to remember which stored files belong to a content hash
+fn record_upload_hash(db: &Db, hash: &str, saved: &SavedMedia) {
+    let key = format!("filehash_{}", hash).into_bytes();
+    let value = serde_json::to_vec(saved).expect("Failed to serialize upload");
+    if db.insert(key, value).is_err() {
+        error!("Failed to record upload hash {} in sled db", hash);
+    }
+}
+
+// Define a guard that removes a partially written upload unless it is marked complete.
+// It also runs when the request is dropped mid-upload, e.g. when shutdown cuts it off.
+struct PartialFile<'a> {
+    path: &'a str,
+    complete: bool,
+}
+
+impl Drop for PartialFile<'_> {
+    fn drop(&mut self) {
+        if !self.complete {
+            std::fs::remove_file(self.path).ok();
+        }
+    }
+}
+
+// Helper function to stream a multipart field into a new file, enforcing a size limit.
+// The partial file is removed if the upload is empty or too large, the stream fails or the request is dropped.
+async fn save_field_to_file(
+    field: &mut Field,
+    filepath: &str,
+    max_bytes: usize,
+    kind: &str,
+) -> Result<usize, Error> {
+    let mut partial = PartialFile { path: filepath, complete: false };
+    let path = filepath.to_string();
+    let mut f = web::block(move || std::fs::File::create(&path)).await??;
+    let mut bytes_written = 0;
+
+    while let Some(chunk) = field.next().await {
+        let data = chunk?;
+
+        bytes_written += data.len();
+        if bytes_written > max_bytes {
+            return Err(BoardError::TooLarge(format!(
+                "{} exceeds the maximum upload size of {} MB",
+                kind,
+                max_bytes / (1024 * 1024)
+            ))
+            .into());
+        }
+
+        f = web::block(move || f.write_all(&data).map(|_| f)).await??;
+    }
+
+    // An empty file is left to the guard to remove, callers treat it as no upload at all
+    partial.complete = bytes_written > 0;
+    Ok(bytes_written)
+}
+
+// Helper function to check an image subtype against ALLOWED_IMAGE_FORMATS
+fn is_image_format_allowed(config: &BoardConfig, subtype: &str) -> bool {
+    config.image_formats.iter().any(|format| format == subtype)
+}
+
+// Helper function to work out an image's format from its first bytes, ignoring its filename
+fn detect_image_format(filepath: &str) -> Option<image::ImageFormat> {
+    image::io::Reader::open(filepath).ok()?.with_guessed_format().ok()?.format()
+}
+
+// Helper function to read an image's width and height from its header without decoding the pixels
+fn read_image_dimensions(filepath: &str) -> Option<(u32, u32)> {
+    image::io::Reader::open(filepath).ok()?.with_guessed_format().ok()?.into_dimensions().ok()
+}
+
+// Helper function to check whether WEBP bytes hold an animation.
+// Animated files start with an extended (VP8X) header whose animation flag (0x02) is set.
+fn is_animated_webp(bytes: &[u8]) -> bool {
+    bytes.len() >= 21
+        && &bytes[0..4] == b"RIFF"
+        && &bytes[8..12] == b"WEBP"
+        && &bytes[12..16] == b"VP8X"
+        && bytes[20] & 0x02 != 0
+}
+
+// Helper function to drop EXIF and other metadata from an uploaded image, in place.
+// JPEG and PNG are re-encoded from their decoded pixels, turned upright first since their
+// EXIF orientation is lost with the rest of the metadata. WEBP keeps its image data but
+// loses its EXIF and XMP chunks so animations survive, and GIF is left untouched since
+// re-encoding would flatten its animation (GIFs do not carry EXIF).
+fn strip_image_metadata(filepath: &str, subtype: &str) -> std::io::Result<()> {
+    let stripped = match subtype {
+        "jpeg" | "png" => {
+            let img = image::open(filepath).map_err(std::io::Error::other)?;
+            let img = apply_exif_orientation(img, read_exif_orientation(filepath));
+            let format = if subtype == "jpeg" {
+                image::ImageOutputFormat::Jpeg(90)
+            } else {
+                image::ImageOutputFormat::Png
+            };
+            let mut bytes = std::io::Cursor::new(Vec::new());
+            img.write_to(&mut bytes, format).map_err(std::io::Error::other)?;
+            bytes.into_inner()
+        }
+        "webp" => strip_webp_metadata(&std::fs::read(filepath)?).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "malformed WEBP container")
+        })?,
+        _ => return Ok(()),
+    };
+
+    // Write beside the original and swap it in so a failure never leaves a half-written file
+    let temp_path = format!("{}.tmp", filepath);
+    std::fs::write(&temp_path, stripped)?;
+    std::fs::rename(&temp_path, filepath)
+}
+
+// Helper function to read the EXIF orientation (1-8) of an image, 1 (upright) when it has none
+fn read_exif_orientation(filepath: &str) -> u32 {
+    let file = match std::fs::File::open(filepath) {
+        Ok(file) => file,
+        Err(_) => return 1,
+    };
+    exif::Reader::new()
+        .read_from_container(&mut std::io::BufReader::new(file))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+        })
+        .unwrap_or(1)
+}
+
+// Helper function to rotate and flip decoded pixels so an image with the given EXIF orientation displays upright
+fn apply_exif_orientation(img: image::DynamicImage, orientation: u32) -> image::DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+// Helper function to remove the EXIF and XMP chunks from a WEBP (RIFF) file.
+// Returns None if the bytes are not a well-formed WEBP container.
+fn strip_webp_metadata(bytes: &[u8]) -> Option<Vec<u8>> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WEBP" {
+        return None;
+    }
+
+    let mut output = b"RIFF\0\0\0\0WEBP".to_vec();
+    let mut position = 12;
+    while position + 8 <= bytes.len() {
+        let fourcc = &bytes[position..position + 4];
+        let size = u32::from_le_bytes(bytes[position + 4..position + 8].try_into().ok()?) as usize;
+        // Chunk payloads are padded to an even length
+        let end = position + 8 + size + (size & 1);
+        let chunk = bytes.get(position..end.min(bytes.len()))?;
+
+        if fourcc == b"VP8X" && chunk.len() > 8 {
+            // Clear the EXIF (0x08) and XMP (0x04) flags of the extended header
+            let mut chunk = chunk.to_vec();
+            chunk[8] &= !(0x08 | 0x04);
+            output.extend_from_slice(&chunk);
+        } else if fourcc != b"EXIF" && fourcc != b"XMP " {
+            output.extend_from_slice(chunk);
+        }
+        position = end;
+    }
+
+    let riff_size = u32::try_from(output.len() - 8).ok()?;
+    output[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(output)
+}
+
+// Helper function to write a max_dim x max_dim thumbnail of an uploaded image.
+// Thumbnails are encoded as WEBP to save bandwidth, falling back to the upload's own format.
+// Returns the thumbnail's filename, or None if no thumbnail could be written.
+fn generate_image_thumbnail(filepath: &str, sanitized_filename: &str, thumb_dir: &str, max_dim: u32) -> Option<String> {
+    let img = image::open(filepath).ok()?;
+    let thumb = image::imageops::thumbnail(&img, max_dim, max_dim);
+
+    // The upload's own name stays in the thumbnail's so deletion can find the original
+    let webp_filename = format!("thumb_{}.webp", sanitized_filename);
+    let webp_path = format!("{}{}", thumb_dir, webp_filename);
+    match thumb.save_with_format(&webp_path, image::ImageFormat::WebP) {
+        Ok(()) => return Some(webp_filename),
+        Err(e) => {
+            warn!("Failed to encode WEBP thumbnail for {}: {}", filepath, e);
+            std::fs::remove_file(&webp_path).ok();
+        }
+    }
+
+    let thumb_filename = format!("thumb_{}", sanitized_filename);
+    let thumb_path = format!("{}{}", thumb_dir, thumb_filename);
+    thumb.save(&thumb_path).is_ok().then_some(thumb_filename)
+}
+
+// Helper function to grab the frame one second into a video as a JPEG thumbnail.
+// Returns false when ffmpeg is missing or fails, so callers can fall back to a placeholder.
+fn generate_video_thumbnail(video_path: &str, thumb_path: &str, max_dim: u32) -> bool {
+    let scale = format!("scale={0}:{0}:force_original_aspect_ratio=decrease", max_dim);
+    let status = std::process::Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error", "-ss", "1", "-i", video_path, "-frames:v", "1"])
+        .args(["-vf", &scale, thumb_path])
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status();
+
+    match status {
+        Ok(status) if status.success() && std::path::Path::new(thumb_path).exists() => true,
+        Ok(status) => {
+            error!("ffmpeg failed to create a thumbnail for {}: {}", video_path, status);
+            std::fs::remove_file(thumb_path).ok();
+            false
+        }
+        Err(e) => {
+            warn!("Could not run ffmpeg for video thumbnails: {}", e);
+            false
+        }
+    }
+}
+
+// Helper function to read the first bytes of a file for signature checks
+fn read_file_header(path: &str, len: usize) -> Vec<u8> {
+    use std::io::Read;
+
+    let mut header = Vec::with_capacity(len);
+    if let Ok(file) = std::fs::File::open(path) {
+        file.take(len as u64).read_to_end(&mut header).ok();
+    }
+    header
+}
+
+// Helper function to check that a file starts with an MP4 "ftyp" box
+fn is_valid_mp4(path: &str) -> bool {
+    is_mp4_header(&read_file_header(path, 12))
+}
+
+// Helper function to check for an ISO base media "ftyp" box: a 4-byte big-endian box size
+// large enough for the major brand and minor version, then the box type "ftyp"
+fn is_mp4_header(header: &[u8]) -> bool {
+    if header.len() < 12 || &header[4..8] != b"ftyp" {
+        return false;
+    }
+    let box_size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    box_size >= 16
+}
+
+// Helper function to check for the start of an MP3 file: an ID3v2 tag, or an MPEG audio frame
+// whose 11-bit sync word is all ones
+fn is_mp3_header(header: &[u8]) -> bool {
+    header.starts_with(b"ID3") || (header.len() >= 2 && header[0] == 0xFF && header[1] & 0xE0 == 0xE0)
+}
+
+// Helper function to check that a file starts with the EBML signature used by WebM
+fn is_valid_webm(path: &str) -> bool {
+    read_file_header(path, 4) == [0x1A, 0x45, 0xDF, 0xA3]
+}
+
+// Helper function to map a media URL to the file serving it on disk
+fn media_file_path(paths: &DataPaths, media_url: &str) -> Option<String> {
+    let (dir, name) = if let Some(name) = media_url.strip_prefix("/thumbs/images/") {
+        (&paths.image_thumbs, name)
+    } else if let Some(name) = media_url.strip_prefix("/uploads/images/") {
+        (&paths.image_uploads, name)
+    } else if let Some(name) = media_url.strip_prefix("/uploads/videos/") {
+        (&paths.video_uploads, name)
+    } else if let Some(name) = media_url.strip_prefix("/uploads/audio/") {
+        (&paths.audio_uploads, name)
+    } else {
+        return None;
+    };
+
+    // Never follow a stored URL outside of the media directories
+    if name.contains("..") || name.contains('/') {
+        return None;
+    }
+    Some(format!("{}{}", dir, name))
+}
+
+// Function to check whether any remaining thread or reply still shows a media URL.
+// Deduplicated uploads can be shared between posts, so files are only removed once unused.
+fn media_in_use(db: &Db, media_url: &str) -> bool {
+    let uses_url = |url: &Option<String>| url.as_deref() == Some(media_url);
+
+    all_boards().any(|board| {
+        get_all_threads_for_board(db, board)
+            .iter()
+            .any(|thread| uses_url(&thread.media_url) || uses_url(&thread.thumb_url))
+            || db
+                .scan_prefix(format!("{}reply_", board_key_prefix(board)))
+                .values()
+                .flatten()
+                .any(|value| {
+                    serde_json::from_slice::<Reply>(&value)
+                        .map(|reply| uses_url(&reply.media_url) || uses_url(&reply.thumb_url))
+                        .unwrap_or(false)
+                })
+    })
+}
+
+// Helper function to remove the files behind a media URL from disk.
+// Thumbnails also take their original upload with them.
+fn delete_media_files(paths: &DataPaths, media_url: &str) {
+    let mut files: Vec<String> = media_file_path(paths, media_url).into_iter().collect();
+
+    if let Some(original) = media_url.strip_prefix("/thumbs/images/thumb_") {
+        // WEBP thumbnails keep the upload's name in front of their own extension
+        let original = match original.strip_suffix(".webp") {
+            Some(upload) if upload.contains('.') => upload,
+            _ => original,
+        };
+        files.extend(media_file_path(paths, &format!("/uploads/images/{}", original)));
+    }
+
+    for path in files {
+        if let Err(e) = std::fs::remove_file(&path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                error!("Failed to remove media file {}: {}", path, e);
+            }
+        }
+    }
+}
+
+// Helper function to build the Sled key prefix for a thread's replies.
+// The trailing underscore keeps thread 1 from matching the replies of thread 11.
+fn reply_prefix(board: &str, parent_id: i32) -> String {
+    format!("{}reply_{}_", board_key_prefix(board), parent_id)
+}
+
+// Helper function to build the Sled key of a single reply
+fn reply_key(board: &str, parent_id: i32, reply_id: i32) -> Vec<u8> {
+    format!("{}reply_{}_{}", board_key_prefix(board), parent_id, reply_id).into_bytes()
+}
+
+// Function to fetch all replies for a given thread from the Sled database
+fn get_replies(db: &Db, board: &str, parent_id: i32) -> Vec<Reply> {
+    let mut replies = db.scan_prefix(reply_prefix(board, parent_id))
+        .filter_map(decode_scanned_record)
+        .collect::<Vec<Reply>>();
+
+    // Keys sort as strings (reply_1_10 before reply_1_2), so order by ID instead
+    replies.sort_by_key(|reply| reply.id);
+    replies
+}
+
+// Function to fetch the most recent replies of a thread, oldest first, along with its reply count.
+// Only keys are scanned for the whole thread; just the chosen replies are read and decoded.
+fn get_latest_replies(db: &Db, board: &str, parent_id: i32, limit: usize) -> (i32, Vec<Reply>) {
+    let prefix = reply_prefix(board, parent_id);
+    let mut reply_ids: Vec<i32> = db
+        .scan_prefix(&prefix)
+        .keys()
+        .flatten()
+        .filter_map(|key| std::str::from_utf8(&key[prefix.len()..]).ok()?.parse().ok())
+        .collect();
+    let reply_count = reply_ids.len() as i32;
+
+    // Keys sort as strings, so pick the newest by ID
+    reply_ids.sort_unstable();
+    let replies = reply_ids[reply_ids.len().saturating_sub(limit)..]
+        .iter()
+        .filter_map(|reply_id| {
+            let key = reply_key(board, parent_id, *reply_id);
+            let value = db.get(&key).transpose()?;
+            decode_scanned_record(value.map(|value| (sled::IVec::from(key), value)))
+        })
+        .collect();
+    (reply_count, replies)
+}
+
+// Function to count the total number of replies for a given thread
+fn count_replies(db: &Db, board: &str, parent_id: i32) -> i32 {
+    db.scan_prefix(reply_prefix(board, parent_id)).count() as i32
+}
+
+// Function to count the replies of every thread on a board in a single scan
+fn count_all_replies(db: &Db, board: &str) -> HashMap<i32, i32> {
+    let mut counts = HashMap::new();
+    for key in db.scan_prefix(board_replies_prefix(board)).keys().flatten() {
+        if let Some(parent_id) = reply_key_parent_id(board, &key) {
+            *counts.entry(parent_id).or_insert(0) += 1;
+        }
+    }
+    counts
+}
+
+// Function to fetch every reply on a board along with its thread ID
+fn get_all_replies(db: &Db, board: &str) -> Vec<(i32, Reply)> {
+    db.scan_prefix(board_replies_prefix(board))
+        .filter_map(|res| {
+            let (key, value) = res.ok()?;
+            let parent_id = reply_key_parent_id(board, &key)?;
+            let reply = serde_json::from_slice(&value).ok()?;
+            Some((parent_id, reply))
+        })
+        .collect()
+}
+
+// Helper function to build the Sled key prefix shared by every reply on a board
+fn board_replies_prefix(board: &str) -> String {
+    format!("{}reply_", board_key_prefix(board))
+}
+
+// Helper function to read the thread ID out of a board's reply_{parent_id}_{reply_id} key
+fn reply_key_parent_id(board: &str, key: &[u8]) -> Option<i32> {
+    std::str::from_utf8(key)
+        .ok()
+        .and_then(|key| key.strip_prefix(board_replies_prefix(board).as_str()))
+        .and_then(|rest| rest.split('_').next())
+        .and_then(|parent_id| parent_id.parse::<i32>().ok())
+}
+
+// Function to allocate the ID for a thread's next reply from its own counter,
+// or None once the thread has handed out MAX_REPLIES IDs
+fn next_reply_id(db: &Db, board: &str, parent_id: i32) -> sled::Result<Option<i32>> {
+    next_id(db, &reply_counter_key(board, parent_id), MAX_REPLIES, || {
+        get_replies(db, board, parent_id).iter().map(|reply| reply.id).max().unwrap_or(0)
+    })
+}
+
+// Helper function to build the Sled key holding the report on a post (reply 0 is the opening post)
+fn report_key(board: &str, thread_id: i32, reply_id: i32) -> Vec<u8> {
+    format!("{}report_{}_{}", board_key_prefix(board), thread_id, reply_id).into_bytes()
+}
+
+// Helper function to build the Sled key prefix shared by all reports in a thread
+fn report_prefix(board: &str, thread_id: i32) -> String {
+    format!("{}report_{}_", board_key_prefix(board), thread_id)
+}
+
+// Function to retrieve the open reports of every board, newest first
+fn get_all_reports(db: &Db) -> Vec<Report> {
+    let mut reports: Vec<Report> = all_boards()
+        .flat_map(|board| db.scan_prefix(format!("{}report_", board_key_prefix(board))))
+        .filter_map(|item| item.ok())
+        .filter_map(|(_, value)| serde_json::from_slice::<Report>(&value).ok())
+        .collect();
+    reports.sort_by_key(|report| std::cmp::Reverse(report.created_at));
+    reports
+}
+
+// Helper function to build the Sled key holding a thread's reply ID counter
+fn reply_counter_key(board: &str, parent_id: i32) -> String {
+    format!("{}next_reply_id_{}", board_key_prefix(board), parent_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Helper function to open a throwaway Sled database
+    fn temp_db() -> Db {
+        sled::Config::new().temporary(true).open().expect("Failed to open temporary sled db")
+    }
+
+    // Helper function to build a bare reply with the given ID
+    fn test_reply(id: i32) -> Reply {
+        Reply {
+            id,
+            message: format!("Reply {}", id),
+            created_at: 0,
+            name: None,
+            tripcode: None,
+            media_url: None,
+            media_type: None,
+            thumb_url: None,
+            spoiler: false,
+            media_info: None,
+            poster_id: None,
+            country: None,
+            delete_password_hash: None,
+            deleted: false,
+        }
+    }
+
+    // Helper function to build a text-only thread as the form would submit it
+    fn test_new_thread(board: &str, message: &str) -> NewThread {
+        NewThread {
+            board: board.to_string(),
+            name: String::new(),
+            title: String::new(),
+            message: message.to_string(),
+            delete_password: String::new(),
+            spoiler: false,
+            media_url: None,
+            media_type: None,
+            thumb_url: None,
+            media_info: None,
+            ip: "192.0.2.1".to_string(),
+            country: None,
+        }
+    }
+
+    // Helper function to build a text-only reply to a main board thread as the form would submit it
+    fn test_new_reply(parent_id: i32, message: &str) -> NewReply {
+        NewReply {
+            board: MAIN_BOARD.to_string(),
+            parent_id,
+            name: String::new(),
+            message: message.to_string(),
+            delete_password: String::new(),
+            spoiler: false,
+            sage: false,
+            media_url: None,
+            media_type: None,
+            thumb_url: None,
+            media_info: None,
+            ip: "192.0.2.2".to_string(),
+            country: None,
+        }
+    }
+
+    #[test]
+    fn insert_thread_rejects_invalid_threads() {
+        let db = temp_db();
+        let config = BoardConfig::from_env();
+
+        let result = insert_thread(&db, &config, test_new_thread("nope", "Hello"));
+        assert!(matches!(result, Err(BoardError::NotFound(..))));
+        let result = insert_thread(&db, &config, test_new_thread(MAIN_BOARD, " \n\n "));
+        assert!(matches!(result, Err(BoardError::BadRequest(_))));
+        let mut long_title = test_new_thread(MAIN_BOARD, "Hello");
+        long_title.title = "x".repeat(MAX_TITLE_CHARS + 1);
+        assert!(matches!(insert_thread(&db, &config, long_title), Err(BoardError::BadRequest(_))));
+
+        // Refused threads use up no IDs
+        assert!(get_all_threads_for_board(&db, MAIN_BOARD).is_empty());
+        let (_, thread) = insert_thread(&db, &config, test_new_thread(MAIN_BOARD, "Hello")).unwrap();
+        assert_eq!(thread.id, 1);
+    }
+
+    #[test]
+    fn length_limits_count_characters_not_bytes() {
+        let db = temp_db();
+        let config = BoardConfig::from_env();
+
+        let mut longest_title = test_new_thread(MAIN_BOARD, "Hello");
+        longest_title.title = "é".repeat(MAX_TITLE_CHARS);
+        let (_, thread) = insert_thread(&db, &config, longest_title).unwrap();
+        assert_eq!(thread.title.chars().count(), MAX_TITLE_CHARS);
+
+        let mut long_title = test_new_thread(MAIN_BOARD, "Hello");
+        long_title.title = "é".repeat(MAX_TITLE_CHARS + 1);
+        assert!(matches!(insert_thread(&db, &config, long_title), Err(BoardError::BadRequest(_))));
+        let result = insert_thread(&db, &config, test_new_thread(MAIN_BOARD, &"スレ".repeat(MAX_MESSAGE_CHARS / 2 + 1)));
+        assert!(matches!(result, Err(BoardError::BadRequest(_))));
+    }
+
+    #[test]
+    fn insert_thread_numbers_each_board_separately() {
+        let db = temp_db();
+        let config = BoardConfig { max_threads_per_ip: 0, ..BoardConfig::from_env() };
+
+        let (board, first) = insert_thread(&db, &config, test_new_thread(MAIN_BOARD, "  First\n\n\n\nthread  ")).unwrap();
+        assert_eq!((board, first.id, first.message.as_str()), (MAIN_BOARD, 1, "First\n\nthread"));
+        let (_, second) = insert_thread(&db, &config, test_new_thread(MAIN_BOARD, "Second")).unwrap();
+        assert_eq!(second.id, 2);
+        let (board, other) = insert_thread(&db, &config, test_new_thread("tech", "Elsewhere")).unwrap();
+        assert_eq!((board, other.id), ("tech", 1));
+
+        let stored: Vec<i32> = get_all_threads_for_board(&db, MAIN_BOARD).iter().map(|thread| thread.id).collect();
+        assert_eq!(stored.len(), 2);
+        assert!(stored.contains(&1) && stored.contains(&2));
+    }
+
+    #[test]
+    fn insert_thread_enforces_the_per_client_limit() {
+        let db = temp_db();
+        let config = BoardConfig { max_threads_per_ip: 2, ..BoardConfig::from_env() };
+
+        for message in ["One", "Two"] {
+            insert_thread(&db, &config, test_new_thread(MAIN_BOARD, message)).unwrap();
+        }
+        let result = insert_thread(&db, &config, test_new_thread(MAIN_BOARD, "Three"));
+        assert!(matches!(result, Err(BoardError::RateLimited { .. })));
+        // The limit is per board
+        insert_thread(&db, &config, test_new_thread("tech", "Three")).unwrap();
+    }
+
+    #[test]
+    fn insert_reply_numbers_replies_and_bumps_the_thread() {
+        let db = temp_db();
+        let config = BoardConfig::from_env();
+
+        let result = insert_reply(&db, test_new_reply(1, "Nobody home"));
+        assert!(matches!(result, Err(BoardError::NotFound(..))));
+
+        insert_thread(&db, &config, test_new_thread(MAIN_BOARD, "Thread")).unwrap();
+        let result = insert_reply(&db, test_new_reply(1, "   "));
+        assert!(matches!(result, Err(BoardError::BadRequest(_))));
+
+        // A saged reply leaves the thread where it is, a normal one bumps it
+        update_thread(&db, MAIN_BOARD, 1, |thread| thread.last_updated = 0).unwrap();
+        let mut sage = test_new_reply(1, "Saged");
+        sage.sage = true;
+        let (_, _, first) = insert_reply(&db, sage).unwrap();
+        assert_eq!(first.id, 1);
+        assert_eq!(update_thread(&db, MAIN_BOARD, 1, |_| {}).unwrap().unwrap().last_updated, 0);
+        let (_, _, second) = insert_reply(&db, test_new_reply(1, "Bump")).unwrap();
+        assert_eq!(second.id, 2);
+        assert!(update_thread(&db, MAIN_BOARD, 1, |_| {}).unwrap().unwrap().last_updated > 0);
+        assert_eq!(get_replies(&db, MAIN_BOARD, 1).len(), 2);
+
+        // Locked threads refuse replies without using up an ID
+        update_thread(&db, MAIN_BOARD, 1, |thread| thread.locked = true).unwrap();
+        let result = insert_reply(&db, test_new_reply(1, "Too late"));
+        assert!(matches!(result, Err(BoardError::Forbidden("Thread Locked", _))));
+        assert_eq!(get_replies(&db, MAIN_BOARD, 1).len(), 2);
+    }
+
+    #[test]
+    fn concurrent_replies_get_distinct_ids() {
+        const WRITERS: usize = 8;
+        const REPLIES_PER_WRITER: usize = 50;
+
+        let db = Arc::new(temp_db());
+        let writers: Vec<_> = (0..WRITERS)
+            .map(|_| {
+                let db = Arc::clone(&db);
+                std::thread::spawn(move || {
+                    for _ in 0..REPLIES_PER_WRITER {
+                        let reply_id = next_reply_id(&db, MAIN_BOARD, 1).expect("Failed to allocate a reply ID").unwrap();
+                        let value = serde_json::to_vec(&test_reply(reply_id)).unwrap();
+                        db.insert(reply_key(MAIN_BOARD, 1, reply_id), value).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let total = (WRITERS * REPLIES_PER_WRITER) as i32;
+        assert_eq!(count_replies(&db, MAIN_BOARD, 1), total);
+        let mut ids: Vec<i32> = get_replies(&db, MAIN_BOARD, 1).iter().map(|reply| reply.id).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, (1..=total).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn concurrent_replies_never_exceed_the_reply_cap() {
+        let db = Arc::new(temp_db());
+        let config = BoardConfig::from_env();
+        insert_thread(&db, &config, test_new_thread(MAIN_BOARD, "Nearly full")).unwrap();
+        for reply_id in 1..=MAX_REPLIES - 2 {
+            let value = serde_json::to_vec(&test_reply(reply_id)).unwrap();
+            db.insert(reply_key(MAIN_BOARD, 1, reply_id), value).unwrap();
+        }
+
+        // Eight replies race for the last two places
+        let writers: Vec<_> = (0..8)
+            .map(|_| {
+                let db = Arc::clone(&db);
+                std::thread::spawn(move || insert_reply(&db, test_new_reply(1, "Last one")).is_ok())
+            })
+            .collect();
+        let accepted = writers.into_iter().map(|writer| writer.join().unwrap()).filter(|accepted| *accepted).count();
+
+        assert_eq!(accepted, 2);
+        assert_eq!(count_replies(&db, MAIN_BOARD, 1), MAX_REPLIES);
+        assert!(matches!(insert_reply(&db, test_new_reply(1, "Too late")), Err(BoardError::Forbidden("Thread Full", _))));
+    }
+
+    #[test]
+    fn mp4_headers_need_an_ftyp_box_of_plausible_size() {
+        assert!(is_mp4_header(b"\x00\x00\x00\x18ftypmp42"));
+        assert!(is_mp4_header(b"\x00\x00\x00\x20ftypisom\x00\x00\x02\x00"));
+        assert!(!is_mp4_header(b"\x00\x00\x00\x0cftypmp42"), "a box too small for its own brand");
+        assert!(!is_mp4_header(b"\x00\x00\x00\x18ftypmp4"), "a header shorter than 12 bytes");
+        assert!(!is_mp4_header(b""));
+        assert!(!is_mp4_header(b"MZ\x90\x00\x03\x00\x00\x00\x04\x00\x00\x00"), "a renamed Windows executable");
+        assert!(!is_mp4_header(b"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00"), "a renamed ELF binary");
+
+        // WebM files are told apart by their EBML signature instead
+        let webm = [0x1A, 0x45, 0xDF, 0xA3, 0x9F, 0x42, 0x86, 0x81, 0x01, 0x42, 0xF7, 0x81];
+        assert!(!is_mp4_header(&webm));
+        let (data_dir, paths) = temp_data_dir();
+        let path = format!("{}test.webm", paths.video_uploads);
+        std::fs::write(&path, webm).unwrap();
+        assert!(is_valid_webm(&path));
+        assert!(!is_valid_mp4(&path));
+        std::fs::remove_dir_all(&data_dir).ok();
+    }
+
+    #[test]
+    fn mp3_headers_need_an_id3_tag_or_frame_sync() {
+        assert!(is_mp3_header(b"ID3\x04"));
+        assert!(is_mp3_header(&[0xFF, 0xFB, 0x90, 0x64]));
+        assert!(is_mp3_header(&[0xFF, 0xE3, 0x18, 0xC4]));
+        assert!(!is_mp3_header(&[0xFF, 0xD8, 0xFF, 0xE0]), "a renamed JPEG");
+        assert!(!is_mp3_header(b"OggS"));
+        assert!(!is_mp3_header(&[0xFF]));
+        assert!(!is_mp3_header(b""));
+    }
+
+    #[actix_web::test]
+    async fn audio_uploads_must_match_their_declared_type() {
+        let (data_dir, paths) = temp_data_dir();
+        let state = test_state(&paths);
+        let app = actix_web::test::init_service(build_app(state.clone())).await;
+
+        let uploads: [(&str, &str, &[u8], actix_web::http::StatusCode); 4] = [
+            ("a.mp3", "audio/mpeg", b"MZ\x90\x00not audio", actix_web::http::StatusCode::BAD_REQUEST),
+            ("b.ogg", "audio/ogg", b"ID3\x04 an mp3 calling itself ogg", actix_web::http::StatusCode::BAD_REQUEST),
+            ("c.mp3", "audio/mpeg", b"ID3\x04 tagged mp3", actix_web::http::StatusCode::SEE_OTHER),
+            ("d.ogg", "audio/ogg", b"OggS ogg stream", actix_web::http::StatusCode::SEE_OTHER),
+        ];
+        for (index, (filename, content_type, bytes, status)) in uploads.into_iter().enumerate() {
+            let body = multipart_body(&[
+                ("captcha", None, "text/plain", b"4"),
+                ("message", None, "text/plain", b"Listen"),
+                ("media", Some(filename), content_type, bytes),
+            ]);
+            let ip = format!("192.0.2.{}", 32 + index);
+            let request = multipart_request("/thread", &ip, test_captcha(&state.captchas), body).to_request();
+            let response = actix_web::test::call_service(&app, request).await;
+            assert_eq!(response.status(), status, "uploading {}", filename);
+        }
+
+        // Only the two genuine files were kept
+        let mut kept: Vec<String> = std::fs::read_dir(&paths.audio_uploads)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().rsplit('.').next().unwrap().to_string())
+            .collect();
+        kept.sort();
+        assert_eq!(kept, ["mp3", "ogg"]);
+        std::fs::remove_dir_all(&data_dir).ok();
+    }
+
+    #[test]
+    fn client_ip_only_believes_trusted_proxies() {
+        let config = BoardConfig {
+            trusted_proxies: vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+            ..BoardConfig::from_env()
+        };
+        let client_ip_of = |peer: &str, forwarded: &str| {
+            let req = actix_web::test::TestRequest::default()
+                .peer_addr(test_peer(peer))
+                .insert_header(("X-Forwarded-For", forwarded))
+                .app_data(web::Data::new(config.clone()))
+                .to_http_request();
+            client_ip(&req)
+        };
+        assert_eq!(client_ip_of("198.51.100.7", "203.0.113.9"), "198.51.100.7");
+        assert_eq!(client_ip_of("10.0.0.1", "203.0.113.9"), "203.0.113.9");
+        assert_eq!(client_ip_of("10.0.0.1", "203.0.113.9, 198.51.100.7, 10.0.0.2"), "198.51.100.7");
+        assert_eq!(client_ip_of("10.0.0.1", "10.0.0.2"), "10.0.0.2");
+        assert_eq!(client_ip_of("10.0.0.1", "not an ip"), "10.0.0.1");
+        assert_eq!(client_ip_of("::1", "203.0.113.9"), "::1");
+    }
+
+    #[test]
+    fn deletion_attempts_are_limited_per_client() {
+        let delete_attempts = DeleteAttempts::default();
+        for _ in 0..MAX_DELETE_ATTEMPTS {
+            assert!(take_delete_attempt(&delete_attempts, "192.0.2.60").is_ok());
+        }
+        let result = take_delete_attempt(&delete_attempts, "192.0.2.60");
+        assert!(matches!(result, Err(BoardError::RateLimited { retry_after: Some(_), .. })));
+        assert!(take_delete_attempt(&delete_attempts, "192.0.2.61").is_ok());
+
+        // A window that has passed starts over
+        delete_attempts.lock().unwrap().get_mut("192.0.2.60").unwrap().1 = Instant::now() - DELETE_ATTEMPT_WINDOW;
+        assert!(take_delete_attempt(&delete_attempts, "192.0.2.60").is_ok());
+        prune_delete_attempts(&delete_attempts);
+        assert_eq!(delete_attempts.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn normalize_message_collapses_blank_lines() {
+        assert_eq!(normalize_message("a\n\n\n\n\nb"), "a\n\nb");
+        assert_eq!(normalize_message("a\r\n\r\n\r\nb"), "a\n\nb");
+        assert_eq!(normalize_message("a  \n \t\nb\t"), "a\n\nb");
+        assert_eq!(normalize_message("a\nb\n\nc"), "a\nb\n\nc");
+        assert_eq!(normalize_message("\n\n  >quote\n\n"), ">quote");
+        assert_eq!(normalize_message("  indented\n    code"), "indented\n    code");
+    }
 
-    let start_index = ((page_number - 1) * page_size) as usize;
-    let end_index = (start_index + page_size as usize).min(threads.len());
-    let threads = &threads[start_index..end_index];
+    #[test]
+    fn reply_ids_continue_after_existing_replies() {
+        let db = temp_db();
+        for id in 1..=3 {
+            db.insert(reply_key(MAIN_BOARD, 7, id), serde_json::to_vec(&test_reply(id)).unwrap()).unwrap();
+        }
+        assert_eq!(next_reply_id(&db, MAIN_BOARD, 7).unwrap(), Some(4));
+        assert_eq!(next_reply_id(&db, MAIN_BOARD, 7).unwrap(), Some(5));
+    }
 
-    // Generate HTML for the list of threads
-    let thread_list_html = if threads.is_empty() {
-        "<p>No threads found. Be the first to create one!</p>".to_string()
-    } else {
-        threads.iter().map(render_thread).collect::<Vec<String>>().join("<hr>")
-    };
+    // Boundary used by the multipart bodies built in tests
+    const TEST_BOUNDARY: &str = "test-boundary";
 
-    // Generate HTML for pagination controls
-    let mut pagination_html = String::new();
+    // Helper function to create media folders beneath a fresh temporary data directory
+    fn temp_data_dir() -> (std::path::PathBuf, DataPaths) {
+        let data_dir = std::env::temp_dir().join(format!("imageboard-test-{}", Uuid::new_v4()));
+        let paths = DataPaths::in_dir(data_dir.to_str().unwrap());
+        for dir in paths.media_dirs() {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        (data_dir, paths)
+    }
 
-    pagination_html.push_str(r#"<div class="pagination">"#);
+    // Helper function to encode a blank square PNG, different sizes giving different files
+    fn test_png(size: u32) -> Vec<u8> {
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::DynamicImage::new_rgb8(size, size).write_to(&mut png, image::ImageOutputFormat::Png).unwrap();
+        png.into_inner()
+    }
 
-    if page_number > 1 {
-        pagination_html.push_str(&format!(
-            r#"<a href="/?page={}">Previous</a>"#,
-            page_number - 1
-        ));
+    // Helper function to build a multipart/form-data body from (name, filename, content type, bytes) parts
+    fn multipart_body(parts: &[(&str, Option<&str>, &str, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (name, filename, content_type, bytes) in parts {
+            let filename = filename.map(|filename| format!("; filename=\"{}\"", filename)).unwrap_or_default();
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"{}\r\nContent-Type: {}\r\n\r\n",
+                    TEST_BOUNDARY, name, filename, content_type
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(bytes);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", TEST_BOUNDARY).as_bytes());
+        body
     }
 
-    for page in 1..=total_pages {
-        if page == page_number {
-            pagination_html.push_str(&format!(
-                r#"<span class="current">{}</span>"#,
-                page
-            ));
-        } else {
-            pagination_html.push_str(&format!(
-                r#"<a href="/?page={}">{}</a>"#,
-                page, page
-            ));
+    // Helper function to build the state of an app backed by a temporary database and the given media folders
+    fn test_state(paths: &DataPaths) -> AppState {
+        AppState {
+            db: Arc::new(temp_db()),
+            config: BoardConfig::from_env(),
+            paths: paths.clone(),
+            post_times: PostTimes::default(),
+            delete_attempts: DeleteAttempts::default(),
+            recent_content: RecentContent::default(),
+            captchas: Captchas::default(),
+            thread_events: ThreadEvents::default(),
+            geoip: None,
+            metrics: Arc::new(Metrics::default()),
         }
     }
 
-    if page_number < total_pages {
-        pagination_html.push_str(&format!(
-            r#"<a href="/?page={}">Next</a>"#,
-            page_number + 1
-        ));
+    // Helper function to open a CAPTCHA challenge whose answer is 4, returning the cookie that answers it
+    fn test_captcha(captchas: &Captchas) -> Cookie<'static> {
+        let token = Uuid::new_v4().to_string();
+        captchas.lock().unwrap().insert(
+            token.clone(),
+            Captcha { answer: 4, expires_at: Instant::now() + Duration::from_secs(60) },
+        );
+        Cookie::new(CAPTCHA_COOKIE, token)
     }
 
-    pagination_html.push_str(r#"</div>"#);
+    // Helper function to build the address a test request connects from
+    fn test_peer(ip: &str) -> std::net::SocketAddr {
+        std::net::SocketAddr::new(ip.parse().unwrap(), 40000)
+    }
 
-    // Assemble the complete HTML for the homepage
-    let html = format!(
-        r#"<!DOCTYPE html>
-<html lang="en">
-<head>
-    <meta charset="UTF-8">
-    <meta name="viewport" content="width=device-width, initial-scale=1.0">
-    <title>Rust Lang is god!</title>
-    <link rel="stylesheet" href="/static/style.css">
-    <script defer src="/static/script.js"></script>
-</head>
-<body>
-    <div class="logo">Rust Simple Imageboard 4</div>
-    <hr>
+    // Helper function to build a multipart form POST sent from the given client address
+    fn multipart_request(uri: &str, ip: &str, captcha: Cookie<'static>, body: Vec<u8>) -> actix_web::test::TestRequest {
+        actix_web::test::TestRequest::post()
+            .uri(uri)
+            .insert_header((header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", TEST_BOUNDARY)))
+            .peer_addr(test_peer(ip))
+            .cookie(captcha)
+            .set_payload(body)
+    }
 
-    <!-- Create Thread Form -->
-    <div id="post-form-container">
-        <form class="postform" action="/thread" method="post" enctype="multipart/form-data">
-            <input type="text" id="title" name="title" maxlength="75" placeholder="Title" required aria-label="Title">
+    #[actix_web::test]
+    async fn posted_thread_and_reply_show_up_on_their_pages() {
+        let (data_dir, paths) = temp_data_dir();
+        let state = test_state(&paths);
+        let app = actix_web::test::init_service(build_app(state.clone())).await;
 
-            <textarea id="message" name="message" rows="4" maxlength="8000" placeholder="Message" required aria-label="Message"></textarea>
+        let body = multipart_body(&[
+            ("captcha", None, "text/plain", b"4"),
+            ("title", None, "text/plain", b"Integration thread"),
+            ("message", None, "text/plain", b"Opening post"),
+        ]);
+        let request = multipart_request("/thread", "192.0.2.10", test_captcha(&state.captchas), body).to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::SEE_OTHER);
 
-            <label for="media">Upload Media (JPEG, PNG, GIF, WEBP, MP4 - optional):</label>
-            <input type="file" id="media" name="media" accept=".jpg,.jpeg,.png,.gif,.webp,.mp4">
+        let homepage = actix_web::test::call_and_read_body(&app, actix_web::test::TestRequest::get().uri("/").to_request()).await;
+        let homepage = String::from_utf8_lossy(&homepage);
+        assert!(homepage.contains(&format!("<title>{}</title>", state.config.title)));
+        assert!(homepage.contains("Integration thread"), "the homepage does not list the new thread");
+        assert!(homepage.contains("Opening post"));
 
-            <input type="submit" value="Create Thread">
-        </form>
-    </div>
-    <hr>
+        // Replying from a second client, as the first one is still cooling down
+        let body = multipart_body(&[
+            ("captcha", None, "text/plain", b"4"),
+            ("parent_id", None, "text/plain", b"1"),
+            ("message", None, "text/plain", b"First reply"),
+        ]);
+        let request = multipart_request("/reply", "192.0.2.11", test_captcha(&state.captchas), body).to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::SEE_OTHER);
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "/thread/1");
 
-    <!-- Thread List -->
-    <div class="postlists">
-        {}
-    </div>
+        let thread_page = actix_web::test::call_and_read_body(&app, actix_web::test::TestRequest::get().uri("/thread/1").to_request()).await;
+        let thread_page = String::from_utf8_lossy(&thread_page);
+        assert!(thread_page.contains("Opening post"));
+        assert!(thread_page.contains("First reply"), "the thread view does not show the reply");
+        std::fs::remove_dir_all(&data_dir).ok();
+    }
 
-    <!-- Pagination Controls -->
-    {}
+    #[actix_web::test]
+    async fn posts_without_a_message_are_rejected() {
+        let (data_dir, paths) = temp_data_dir();
+        let state = test_state(&paths);
+        let app = actix_web::test::init_service(build_app(state.clone())).await;
 
-    <div class="footer">
-        - Powered by Rust and Actix Web -
-    </div>
-</body>
-</html>"#,
-        thread_list_html,
-        pagination_html
-    );
+        // The subject is optional, but a thread without a message is refused
+        let body = multipart_body(&[
+            ("captcha", None, "text/plain", b"4"),
+            ("title", None, "text/plain", b"  "),
+            ("message", None, "text/plain", b" \r\n "),
+        ]);
+        let request = multipart_request("/thread", "192.0.2.20", test_captcha(&state.captchas), body).to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        assert!(get_all_threads_for_board(&state.db, MAIN_BOARD).is_empty());
 
-    HttpResponse::Ok().content_type("text/html").body(html)
-}
+        // So is an empty reply to a thread that exists
+        insert_thread(&state.db, &state.config, test_new_thread(MAIN_BOARD, "Hello")).unwrap();
+        let body = multipart_body(&[
+            ("captcha", None, "text/plain", b"4"),
+            ("parent_id", None, "text/plain", b"1"),
+            ("message", None, "text/plain", b""),
+        ]);
+        let request = multipart_request("/reply", "192.0.2.21", test_captcha(&state.captchas), body).to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        assert!(get_replies(&state.db, MAIN_BOARD, 1).is_empty());
+        std::fs::remove_dir_all(&data_dir).ok();
+    }
 
-// Helper function to render individual threads
-fn render_thread(thread: &Thread) -> String {
-    let media_html = if let (Some(ref url), Some(ref media_type)) = (&thread.media_url, &thread.media_type) {
-        match media_type {
-            MediaType::Image => {
-                // Check if the image is a GIF by its extension
-                if url.to_lowercase().ends_with(".gif") {
-                    format!(
-                        r#"<div class="post-media">
-    <img src="{}" alt="Thread Image" class="toggle-image">
-</div>"#,
-                        escape_html(url)
-                    )
-                } else {
-                    format!(
-                        r#"<div class="post-media">
-    <img src="{}" alt="Thread Image" class="toggle-image">
-</div>"#,
-                        escape_html(url)
-                    )
-                }
+    // Helper function to read the first field of a multipart body delivered in the given chunks
+    async fn read_first_text_field(chunks: Vec<Vec<u8>>) -> Result<String, Error> {
+        let mut headers = actix_web::http::header::HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_str(&format!("multipart/form-data; boundary={}", TEST_BOUNDARY)).unwrap(),
+        );
+        // Hold back every chunk for one poll, so that each one arrives separately as it would from a slow client
+        let mut chunks = chunks.into_iter();
+        let mut held_back = false;
+        let stream = futures_util::stream::poll_fn(move |cx| {
+            held_back = !held_back;
+            if held_back {
+                cx.waker().wake_by_ref();
+                return std::task::Poll::Pending;
             }
-            MediaType::Video => format!(
-                r#"<div class="post-media">
-    <video controls class="video-player">
-        <source src="{}" type="video/mp4">
-        Your browser does not support the video tag.
-    </video>
-</div>"#,
-                escape_html(url)
-            ),
+            std::task::Poll::Ready(
+                chunks.next().map(|chunk| Ok::<_, actix_web::error::PayloadError>(actix_web::web::Bytes::from(chunk))),
+            )
+        });
+        let mut multipart = Multipart::new(&headers, stream);
+        let mut field = multipart.next().await.unwrap()?;
+        read_text_field(&mut field).await
+    }
+
+    #[actix_web::test]
+    async fn multibyte_title_split_across_chunks_is_preserved() {
+        let title = "Ünïcödé スレッド";
+        let body = multipart_body(&[("title", None, "text/plain", title.as_bytes())]);
+
+        // Send the part headers, then cut the title at every byte, including the middle of each multibyte character
+        let start = body.windows(title.len()).position(|window| window == title.as_bytes()).unwrap();
+        for split in start + 1..start + title.len() {
+            let chunks = vec![body[..start].to_vec(), body[start..split].to_vec(), body[split..].to_vec()];
+            assert_eq!(read_first_text_field(chunks).await.unwrap(), title, "split at byte {}", split);
         }
-    } else {
-        "".to_string()
-    };
 
-    format!(
-        r#"<div class="post thread-post">
-    {}
-    <div class="post-content">
-        <div class="post-header">
-            <span class="title">{}</span>
-            <a href="/thread/{}" class="reply-link">Reply</a>
-        </div>
-        <div class="message">{}</div>
-    </div>
-</div>"#,
-        media_html,
-        escape_html(&thread.title),
-        thread.id,
-        escape_html(&thread.message)
-    )
-}
+        let body = multipart_body(&[("title", None, "text/plain", b"bad \xff byte")]);
+        let error = read_first_text_field(vec![body]).await.unwrap_err();
+        assert_eq!(error.as_response_error().status_code(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
 
-// Function to fetch all threads from the Sled database
-fn get_all_threads(db: &Db) -> Vec<Thread> {
-    db.scan_prefix(b"thread_")
-        .filter_map(|res| {
-            if let Ok((_, value)) = res {
-                serde_json::from_slice(&value).ok()
-            } else {
-                None
+    #[actix_web::test]
+    async fn threads_bumped_together_page_without_duplicates() {
+        let (data_dir, paths) = temp_data_dir();
+        let state = AppState { config: BoardConfig { page_size: 3, max_threads_per_ip: 0, ..BoardConfig::from_env() }, ..test_state(&paths) };
+        for _ in 0..8 {
+            insert_thread(&state.db, &state.config, test_new_thread(MAIN_BOARD, "Same second")).unwrap();
+        }
+        for thread_id in 1..=8 {
+            update_thread(&state.db, MAIN_BOARD, thread_id, |thread| {
+                thread.created_at = 0;
+                thread.last_updated = 1_700_000_000;
+            })
+            .unwrap();
+        }
+        let app = actix_web::test::init_service(build_app(state)).await;
+
+        let mut seen = Vec::new();
+        for page in 1..=3 {
+            let request = actix_web::test::TestRequest::get().uri(&format!("/api/threads?page={}", page)).to_request();
+            let body: serde_json::Value = actix_web::test::call_and_read_body_json(&app, request).await;
+            for thread in body["threads"].as_array().unwrap() {
+                assert_eq!(thread["updated_at"], 1_700_000_000);
+                assert_eq!(thread["created_at"], 1_700_000_000);
+                seen.push(thread["id"].as_i64().unwrap());
             }
-        })
-        .collect()
-}
+        }
+        assert_eq!(seen, vec![8, 7, 6, 5, 4, 3, 2, 1]);
+        std::fs::remove_dir_all(&data_dir).ok();
+    }
 
-// Function to count the total number of threads
-fn count_threads(db: &Db) -> i32 {
-    db.scan_prefix(b"thread_").count() as i32
-}
+    #[actix_web::test]
+    async fn api_threads_are_read_from_the_board_they_were_created_on() {
+        let (data_dir, paths) = temp_data_dir();
+        let state = test_state(&paths);
+        let app = actix_web::test::init_service(build_app(state.clone())).await;
 
-// Handler to view a specific thread and its replies
-async fn view_thread(
-    db: web::Data<Arc<Db>>,
-    path: web::Path<(i32,)>,
-) -> impl Responder {
-    let thread_id = path.into_inner().0;
-    let thread_key = format!("thread_{}", thread_id).into_bytes();
-    let thread: Option<Thread> = db.get(&thread_key).ok().flatten().and_then(|value| {
-        serde_json::from_slice(&value).ok()
-    });
+        let request = actix_web::test::TestRequest::post()
+            .uri("/api/thread")
+            .peer_addr(test_peer("192.0.2.96"))
+            .cookie(test_captcha(&state.captchas))
+            .set_json(serde_json::json!({ "board": "tech", "message": "Posted through the API", "captcha": "4" }))
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::CREATED);
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "/api/tech/thread/1");
+        insert_reply(&state.db, NewReply { board: "tech".to_string(), ..test_new_reply(1, "API reply") }).unwrap();
 
-    if thread.is_none() {
-        return HttpResponse::NotFound()
-            .content_type("text/html")
-            .body(render_error_page("Thread Not Found", "The requested thread does not exist."));
+        let request = actix_web::test::TestRequest::get().uri("/api/tech/thread/1").to_request();
+        let body: serde_json::Value = actix_web::test::call_and_read_body_json(&app, request).await;
+        assert_eq!(body["thread"]["message"], "Posted through the API");
+        assert_eq!(body["replies"][0]["message"], "API reply");
+        let request = actix_web::test::TestRequest::get().uri("/api/tech/thread/1/reply/1").to_request();
+        let body: serde_json::Value = actix_web::test::call_and_read_body_json(&app, request).await;
+        assert_eq!(body["message"], "API reply");
+        let request = actix_web::test::TestRequest::get().uri("/api/tech/threads").to_request();
+        let body: serde_json::Value = actix_web::test::call_and_read_body_json(&app, request).await;
+        assert_eq!(body["pagination"]["total_threads"], 1);
+
+        // The main board and unknown boards do not have it
+        let request = actix_web::test::TestRequest::get().uri("/api/threads").to_request();
+        let body: serde_json::Value = actix_web::test::call_and_read_body_json(&app, request).await;
+        assert_eq!(body["pagination"]["total_threads"], 0);
+        for uri in ["/api/thread/1", "/api/tech/thread/2", "/api/nope/thread/1", "/api/nope/threads"] {
+            let response = actix_web::test::call_service(&app, actix_web::test::TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(response.status(), actix_web::http::StatusCode::NOT_FOUND, "{}", uri);
+        }
+        std::fs::remove_dir_all(&data_dir).ok();
     }
 
-    let thread = thread.unwrap();
-    let replies = get_replies(&db, thread_id);
+    // Helper function to build the state of a test app whose admin password is "test-password"
+    fn test_admin_state(paths: &DataPaths) -> AppState {
+        let config = BoardConfig {
+            admin_password: Some("test-password".to_string()),
+            admin_session: Some(admin_session_token("test-password")),
+            ..BoardConfig::from_env()
+        };
+        AppState { config, ..test_state(paths) }
+    }
 
-    // Generate HTML for the list of replies
-    let replies_html = if replies.is_empty() {
-        "<p>No replies yet. Be the first to reply!</p>".to_string()
-    } else {
-        replies.iter().map(render_reply).collect::<Vec<String>>().join("<hr>")
-    };
+    // Helper function to build a POST carrying the test admin password
+    fn admin_request(uri: &str) -> actix_web::test::TestRequest {
+        actix_web::test::TestRequest::post()
+            .uri(uri)
+            .insert_header((header::AUTHORIZATION, "Bearer test-password"))
+    }
 
-    // Generate HTML for the thread's media if it exists
-    let media_html = if let (Some(ref url), Some(ref media_type)) = (&thread.media_url, &thread.media_type) {
-        match media_type {
-            MediaType::Image => {
-                // Check if the image is a GIF by its extension
-                if url.to_lowercase().ends_with(".gif") {
-                    format!(
-                        r#"<div class="post-media">
-    <img src="{}" alt="Thread Image" class="toggle-image">
-</div>"#,
-                        escape_html(url)
-                    )
-                } else {
-                    format!(
-                        r#"<div class="post-media">
-    <img src="{}" alt="Thread Image" class="toggle-image">
-</div>"#,
-                        escape_html(url)
-                    )
-                }
-            }
-            MediaType::Video => format!(
-                r#"<div class="post-media">
-    <video controls class="video-player">
-        <source src="{}" type="video/mp4">
-        Your browser does not support the video tag.
-    </video>
-</div>"#,
-                escape_html(url)
-            ),
+    #[actix_web::test]
+    async fn admins_moderate_threads_on_every_board() {
+        let (data_dir, paths) = temp_data_dir();
+        let state = test_admin_state(&paths);
+        for board in [MAIN_BOARD, "tech"] {
+            let mut new_thread = test_new_thread(board, "Hello");
+            new_thread.ip = format!("192.0.2.{}", board.len() + 40);
+            insert_thread(&state.db, &state.config, new_thread).unwrap();
         }
-    } else {
-        "".to_string()
-    };
+        let mut new_reply = test_new_reply(1, "Reply on tech");
+        new_reply.board = "tech".to_string();
+        insert_reply(&state.db, new_reply).unwrap();
+        let app = actix_web::test::init_service(build_app(state.clone())).await;
 
-    // Assemble the complete HTML for the thread view
-    let html = format!(
-        r#"<!DOCTYPE html>
-<html lang="en">
-<head>
-    <meta charset="UTF-8">
-    <meta name="viewport" content="width=device-width, initial-scale=1.0">
-    <title>Thread - {}</title>
-    <link rel="stylesheet" href="/static/style.css">
-    <script defer src="/static/script.js"></script>
-</head>
-<body>
-    <!-- Reply Mode Label -->
-    <div class="replymode">
-        <strong>Reply Mode</strong> | <a href="/">Back to Main Board</a>
-    </div>
-    <br>
+        let response = actix_web::test::call_service(&app, admin_request("/tech/reply/1/1/delete").to_request()).await;
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "/tech/thread/1");
+        assert!(get_replies(&state.db, "tech", 1)[0].deleted);
 
-    <!-- Reply Form -->
-    <div class="postarea-container">
-        <form class="postform" action="/reply" method="post">
-            <input type="hidden" name="parent_id" value="{}">
-            
-            <textarea id="message" name="message" rows="4" maxlength="8000" placeholder="Message" required aria-label="Message"></textarea>
+        let response = actix_web::test::call_service(&app, admin_request("/tech/thread/1/sticky").to_request()).await;
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "/tech/");
+        assert!(get_all_threads_for_board(&state.db, "tech")[0].sticky);
+        assert!(!get_all_threads_for_board(&state.db, MAIN_BOARD)[0].sticky);
 
-            <input type="submit" value="Reply">
-        </form>
-    </div>
-    <br>
+        let response = actix_web::test::call_service(&app, admin_request("/tech/thread/1/lock").to_request()).await;
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "/tech/thread/1");
+        assert!(get_all_threads_for_board(&state.db, "tech")[0].locked);
+        assert!(!get_all_threads_for_board(&state.db, MAIN_BOARD)[0].locked);
 
-    <!-- Main Thread -->
-    <div class="post thread-post">
-        {}
-        <div class="post-content">
-            <div class="post-header">
-                <span class="title">{}</span>
-                <!-- Reply Link Removed -->
-            </div>
-            <div class="message">{}</div>
-        </div>
-    </div>
-    <hr>
+        let response = actix_web::test::call_service(&app, admin_request("/tech/thread/1/delete").to_request()).await;
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "/tech/");
+        assert!(get_all_threads_for_board(&state.db, "tech")[0].deleted);
+        assert!(!get_all_threads_for_board(&state.db, MAIN_BOARD)[0].deleted);
 
-    <!-- Replies -->
-    <div class="postlists">
-        {}
-    </div>
-    
-    <div class="footer">
-        - Powered by Rust and Actix Web -
-    </div>
-</body>
-</html>"#,
-        escape_html(&thread.title),
-        thread.id,
-        media_html,
-        escape_html(&thread.title),
-        escape_html(&thread.message),
-        replies_html
-    );
+        // Admins see buttons to restore the deleted posts, and using them brings the posts back
+        let request = actix_web::test::TestRequest::get()
+            .uri("/tech/thread/1")
+            .insert_header((header::AUTHORIZATION, "Bearer test-password"))
+            .to_request();
+        let page = actix_web::test::call_and_read_body(&app, request).await;
+        assert_eq!(String::from_utf8_lossy(&page).matches(r#"value="Restore Post""#).count(), 2);
+        for reply_id in [0, 1] {
+            let request = admin_request("/admin/restore")
+                .set_form([("board", "tech".to_string()), ("thread_id", "1".to_string()), ("reply_id", reply_id.to_string())])
+                .to_request();
+            let response = actix_web::test::call_service(&app, request).await;
+            assert_eq!(response.headers().get(header::LOCATION).unwrap(), "/tech/thread/1");
+        }
+        assert!(!get_all_threads_for_board(&state.db, "tech")[0].deleted);
+        assert!(!get_replies(&state.db, "tech", 1)[0].deleted);
 
-    HttpResponse::Ok().content_type("text/html").body(html)
-}
+        let response = actix_web::test::call_service(&app, admin_request("/nope/thread/1/delete").to_request()).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::NOT_FOUND);
+        std::fs::remove_dir_all(&data_dir).ok();
+    }
 
-// Helper function to render individual replies
-fn render_reply(reply: &Reply) -> String {
-    format!(
-        r#"<div class="post reply-post">
-    <div class="post-content">
-        <div class="post-header">
-            <span class="title">Reply {}</span>
-        </div>
-        <div class="message">{}</div>
-    </div>
-</div>"#,
-        reply.id,
-        escape_html(&reply.message)
-    )
-}
+    #[actix_web::test]
+    async fn unban_matches_the_canonical_address() {
+        let (data_dir, paths) = temp_data_dir();
+        let state = test_admin_state(&paths);
+        let app = actix_web::test::init_service(build_app(state.clone())).await;
 
-// Handler to create a new thread with optional media upload
-async fn create_thread(
-    db: web::Data<Arc<Db>>,
-    mut payload: Multipart,
-) -> Result<HttpResponse, Error> {
-    let mut title = String::new();
-    let mut message = String::new();
-    let mut media_url: Option<String> = None;
-    let mut media_type: Option<MediaType> = None;
+        let request = admin_request("/admin/ban")
+            .set_form([("ip", "2001:DB8:0:0::1"), ("reason", "Spam"), ("hours", "24")])
+            .to_request();
+        assert_eq!(actix_web::test::call_service(&app, request).await.status(), actix_web::http::StatusCode::SEE_OTHER);
+        assert!(active_ban(&state.db, "2001:db8::1").is_some());
 
-    while let Some(item) = payload.next().await {
-        let mut field = item?;
-        let content_disposition = field.content_disposition();
+        // The same address written differently lifts the ban, and lifting it again finds nothing
+        let expected = [
+            ("2001:0db8::0:1", actix_web::http::StatusCode::SEE_OTHER),
+            ("2001:db8::1", actix_web::http::StatusCode::NOT_FOUND),
+            ("not an ip", actix_web::http::StatusCode::BAD_REQUEST),
+        ];
+        for (ip, status) in expected {
+            let request = admin_request("/admin/unban").set_form([("ip", ip)]).to_request();
+            assert_eq!(actix_web::test::call_service(&app, request).await.status(), status, "unbanning {:?}", ip);
+        }
+        assert!(active_ban(&state.db, "2001:db8::1").is_none());
+        std::fs::remove_dir_all(&data_dir).ok();
+    }
 
-        let name = if let Some(name) = content_disposition.get_name() {
-            name
-        } else {
-            continue;
+    #[actix_web::test]
+    async fn deletion_passwords_are_refused_to_banned_and_guessing_clients() {
+        let (data_dir, paths) = temp_data_dir();
+        let state = test_admin_state(&paths);
+        let mut new_thread = test_new_thread(MAIN_BOARD, "Hello");
+        new_thread.delete_password = "secret".to_string();
+        insert_thread(&state.db, &state.config, new_thread).unwrap();
+        let app = actix_web::test::init_service(build_app(state.clone())).await;
+        let delete_request = |ip: &str, password: &str| {
+            actix_web::test::TestRequest::post()
+                .uri("/delete")
+                .peer_addr(test_peer(ip))
+                .set_form([("board", ""), ("thread_id", "1"), ("reply_id", "0"), ("password", password)])
+                .to_request()
         };
 
-        match name {
-            "title" => {
-                while let Some(chunk) = field.next().await {
-                    let data = chunk?;
-                    title.push_str(&String::from_utf8_lossy(&data));
-                }
-            }
-            "message" => {
-                while let Some(chunk) = field.next().await {
-                    let data = chunk?;
-                    message.push_str(&String::from_utf8_lossy(&data));
-                }
-            }
-            "media" => {
-                // Handle media upload
-                if let Some(filename) = content_disposition.get_filename() {
-                    // Skip processing if filename is empty
-                    if filename.trim().is_empty() {
-                        continue;
-                    }
+        // A banned client is turned away before its password is checked
+        let request = admin_request("/admin/ban")
+            .set_form([("ip", "192.0.2.90"), ("reason", "Spam"), ("hours", "24")])
+            .to_request();
+        assert_eq!(actix_web::test::call_service(&app, request).await.status(), actix_web::http::StatusCode::SEE_OTHER);
+        let response = actix_web::test::call_service(&app, delete_request("192.0.2.90", "secret")).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::FORBIDDEN);
+        assert!(!state.delete_attempts.lock().unwrap().contains_key("192.0.2.90"));
 
-                    // Determine the MIME type
-                    let mime_type = mime_guess::from_path(&filename).first_or_octet_stream();
-
-                    match mime_type.type_() {
-                        mime::IMAGE => {
-                            // Supported image subtypes
-                            if !matches!(
-                                mime_type.subtype().as_ref(),
-                                "jpeg" | "png" | "gif" | "webp"
-                            ) {
-                                return Ok(HttpResponse::BadRequest().body("Unsupported image format"));
-                            }
-
-                            // Check if the image is a GIF by its subtype
-                            let is_gif = mime_type.subtype().as_ref() == "gif";
-
-                            // Generate a unique filename
-                            let unique_id = Uuid::new_v4().to_string();
-                            let extension = mime_type.subtype().as_str();
-                            let sanitized_filename = format!("{}.{}", unique_id, extension);
-                            let filepath = format!("{}{}", IMAGE_UPLOAD_DIR, sanitized_filename);
-                            let filepath_clone = filepath.clone(); // Clone the filepath
-
-                            // Save the image file asynchronously
-                            let mut f = web::block(move || std::fs::File::create(&filepath)).await??;
-
-                            while let Some(chunk) = field.next().await {
-                                let data = chunk?;
-                                f = web::block(move || f.write_all(&data).map(|_| f)).await??;
-                            }
-
-                            // Validate the image content using the cloned filepath
-                            if let Err(_) = image::open(&filepath_clone) {
-                                std::fs::remove_file(&filepath_clone)?;
-                                return Ok(HttpResponse::BadRequest().body("Invalid image file"));
-                            }
-
-                            if is_gif {
-                                // For GIFs, skip thumbnail generation
-                                media_url = Some(format!("/uploads/images/{}", sanitized_filename));
-                                media_type = Some(MediaType::Image);
-                            } else {
-                                // Generate a thumbnail for non-GIF images
-                                let thumb_filename = format!("thumb_{}", sanitized_filename);
-                                let thumb_path = format!("{}{}", IMAGE_THUMB_DIR, thumb_filename);
-                                if let Ok(img) = image::open(&filepath_clone) {
-                                    let thumb = image::imageops::thumbnail(&img, 200, 200);
-                                    thumb.save(&thumb_path).ok();
-                                    media_url = Some(format!("/thumbs/images/{}", thumb_filename));
-                                    media_type = Some(MediaType::Image);
-                                }
-
-                                // If thumbnail creation failed, use the original image
-                                if media_url.is_none() {
-                                    media_url = Some(format!("/uploads/images/{}", sanitized_filename));
-                                    media_type = Some(MediaType::Image);
-                                }
-                            }
-                        }
-                        mime::VIDEO => {
-                            // Supported video subtypes
-                            if mime_type.subtype().as_ref() != "mp4" {
-                                return Ok(HttpResponse::BadRequest().body("Unsupported video format"));
-                            }
-
-                            // Generate a unique filename
-                            let unique_id = Uuid::new_v4().to_string();
-                            let extension = mime_type.subtype().as_str();
-                            let sanitized_filename = format!("{}.{}", unique_id, extension);
-                            let filepath = format!("{}{}", VIDEO_UPLOAD_DIR, sanitized_filename);
-
-                            // Save the video file asynchronously
-                            let mut f = web::block(move || std::fs::File::create(&filepath)).await??;
-
-                            while let Some(chunk) = field.next().await {
-                                let data = chunk?;
-                                f = web::block(move || f.write_all(&data).map(|_| f)).await??;
-                            }
-
-                            // Basic validation: check if the file is a valid MP4
-                            // Note: image::open won't validate videos. Consider using a video processing crate for robust validation.
-                            // For simplicity, we'll skip validation here.
-
-                            media_url = Some(format!("/uploads/videos/{}", sanitized_filename));
-                            media_type = Some(MediaType::Video);
-                        }
-                        _ => {
-                            return Ok(HttpResponse::BadRequest().body("Unsupported media type"));
-                        }
-                    }
-                }
-            }
-            _ => {}
+        // A guessing client runs out of attempts, after which even the right password is refused
+        for _ in 0..MAX_DELETE_ATTEMPTS {
+            let response = actix_web::test::call_service(&app, delete_request("192.0.2.91", "guess")).await;
+            assert_eq!(response.status(), actix_web::http::StatusCode::FORBIDDEN);
         }
-    }
+        let response = actix_web::test::call_service(&app, delete_request("192.0.2.91", "secret")).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::TOO_MANY_REQUESTS);
+        assert!(!get_all_threads_for_board(&state.db, MAIN_BOARD)[0].deleted);
 
-    // Ensure that title and message are not empty
-    if title.trim().is_empty() || message.trim().is_empty() {
-        return Ok(HttpResponse::BadRequest()
-            .content_type("text/html")
-            .body(render_error_page("Bad Request", "Title and Message cannot be empty")));
+        let response = actix_web::test::call_service(&app, delete_request("192.0.2.92", "secret")).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::SEE_OTHER);
+        assert!(get_all_threads_for_board(&state.db, MAIN_BOARD)[0].deleted);
+        std::fs::remove_dir_all(&data_dir).ok();
     }
 
-    let thread_id = count_threads(&db) + 1;
-    let thread = Thread {
-        id: thread_id,
-        title: title.trim().to_string(),
-        message: message.trim().to_string(),
-        last_updated: Utc::now().timestamp(),
-        media_url,
-        media_type,
-    };
-
-    let key = format!("thread_{}", thread_id).into_bytes();
-    let value = serde_json::to_vec(&thread).expect("Failed to serialize thread");
+    #[actix_web::test]
+    async fn recent_replies_cover_every_board() {
+        let (data_dir, paths) = temp_data_dir();
+        let state = test_state(&paths);
+        for board in [MAIN_BOARD, "tech"] {
+            let mut new_thread = test_new_thread(board, "Hello");
+            new_thread.ip = format!("192.0.2.{}", board.len() + 50);
+            insert_thread(&state.db, &state.config, new_thread).unwrap();
+            let mut new_reply = test_new_reply(1, &format!("Reply on {:?}", board));
+            new_reply.board = board.to_string();
+            insert_reply(&state.db, new_reply).unwrap();
+        }
+        let app = actix_web::test::init_service(build_app(state)).await;
 
-    if db.insert(key, value).is_ok() {
-        Ok(HttpResponse::SeeOther()
-            .append_header(("Location", "/"))
-            .finish())
-    } else {
-        error!("Failed to insert thread into sled db");
-        Ok(HttpResponse::InternalServerError()
-            .content_type("text/html")
-            .body(render_error_page("Internal Server Error", "Failed to create thread")))
+        let page = actix_web::test::call_and_read_body(&app, actix_web::test::TestRequest::get().uri("/recent").to_request()).await;
+        let page = String::from_utf8_lossy(&page);
+        assert!(page.contains(r#"href="/thread/1#reply-1""#));
+        assert!(page.contains(r#"href="/tech/thread/1#reply-1""#));
+        assert!(page.contains("Reply on &quot;tech&quot;"));
+        std::fs::remove_dir_all(&data_dir).ok();
     }
-}
 
-// Handler to create a new reply to an existing thread
-async fn create_reply(
-    db: web::Data<Arc<Db>>,
-    form: web::Form<ReplyForm>,
-) -> Result<HttpResponse, Error> {
-    let parent_id = form.parent_id;
-    let message = form.message.trim().to_string();
+    #[actix_web::test]
+    async fn catalog_search_and_feed_cover_every_board() {
+        let (data_dir, paths) = temp_data_dir();
+        let state = test_state(&paths);
+        let new_thread = NewThread { title: "Findable subject".to_string(), ..test_new_thread("tech", "Findable opening post") };
+        insert_thread(&state.db, &state.config, new_thread).unwrap();
+        insert_reply(&state.db, NewReply { board: "tech".to_string(), ..test_new_reply(1, "Findable reply") }).unwrap();
+        let app = actix_web::test::init_service(build_app(state)).await;
+        let read_page = |uri: &str| actix_web::test::TestRequest::get().uri(uri).to_request();
 
-    // Ensure that message is not empty
-    if message.is_empty() {
-        return Ok(HttpResponse::BadRequest()
-            .content_type("text/html")
-            .body(render_error_page("Bad Request", "Message cannot be empty")));
+        let index = actix_web::test::call_and_read_body(&app, read_page("/tech/")).await;
+        assert!(String::from_utf8_lossy(&index).contains(r#"<a href="/tech/catalog">Catalog</a> | <a href="/tech/search">Search</a>"#));
+        let catalog = actix_web::test::call_and_read_body(&app, read_page("/tech/catalog")).await;
+        let catalog = String::from_utf8_lossy(&catalog);
+        assert!(catalog.contains(r#"<a href="/tech/thread/1">"#));
+        assert!(catalog.contains("Findable subject"));
+        let results = actix_web::test::call_and_read_body(&app, read_page("/tech/search?q=findable")).await;
+        let results = String::from_utf8_lossy(&results);
+        assert!(results.contains(r#"action="/tech/search""#));
+        assert!(results.contains(r#"href="/tech/thread/1" class="reply-link""#));
+        assert!(results.contains(r#"href="/tech/thread/1#reply-1" class="reply-link""#));
+        let feed = actix_web::test::call_and_read_body(&app, read_page("/tech/feed.xml")).await;
+        let feed = String::from_utf8_lossy(&feed);
+        assert!(feed.contains("<title>/tech/ - Technology</title>"));
+        assert!(feed.contains("/tech/thread/1</link>"));
+
+        // The main board's pages only show its own threads
+        for uri in ["/catalog", "/search?q=findable", "/feed.xml"] {
+            let page = actix_web::test::call_and_read_body(&app, read_page(uri)).await;
+            assert!(!String::from_utf8_lossy(&page).contains("Findable"), "{} shows a /tech/ post", uri);
+        }
+        for uri in ["/nope/catalog", "/nope/search", "/nope/feed.xml"] {
+            let response = actix_web::test::call_service(&app, read_page(uri)).await;
+            assert_eq!(response.status(), actix_web::http::StatusCode::NOT_FOUND, "{}", uri);
+        }
+        std::fs::remove_dir_all(&data_dir).ok();
     }
 
-    let reply_id = count_replies(&db, parent_id) + 1;
-    let reply = Reply {
-        id: reply_id,
-        message,
-    };
+    #[actix_web::test]
+    async fn second_media_part_is_rejected_without_orphaning_the_first() {
+        let (data_dir, paths) = temp_data_dir();
+        let app = actix_web::test::init_service(build_app(test_state(&paths))).await;
 
-    let key = format!("reply_{}_{}", parent_id, reply_id).into_bytes();
-    let value = serde_json::to_vec(&reply).expect("Failed to serialize reply");
+        // Two different valid images in the same post, so the second is not deduplicated into the first
+        let (first, second) = (test_png(4), test_png(5));
+        let two_files = multipart_body(&[
+            ("media", Some("first.png"), "image/png", &first),
+            ("media", Some("second.png"), "image/png", &second),
+        ]);
+        // An upload followed by a part the client never finished sending
+        let mut cut_short = multipart_body(&[
+            ("media", Some("first.png"), "image/png", &first),
+            ("message", None, "text/plain", b"Never finished"),
+        ]);
+        cut_short.truncate(cut_short.windows(8).position(|window| window == b"finished").unwrap());
+        // An upload followed by a subject that is not valid UTF-8
+        let bad_title = multipart_body(&[
+            ("media", Some("first.png"), "image/png", &first),
+            ("title", None, "text/plain", b"bad \xff byte"),
+        ]);
+
+        for (uri, body) in [
+            ("/thread", two_files),
+            ("/thread", cut_short.clone()),
+            ("/reply", cut_short),
+            ("/thread", bad_title),
+        ] {
+            let request = actix_web::test::TestRequest::post()
+                .uri(uri)
+                .insert_header((header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", TEST_BOUNDARY)))
+                .set_payload(body)
+                .to_request();
+            let response = actix_web::test::call_service(&app, request).await;
+            assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
 
-    if db.insert(key, value).is_ok() {
-        // Update thread's last_updated timestamp
-        let thread_key = format!("thread_{}", parent_id).into_bytes();
-        if let Some(thread_bytes) = db.get(&thread_key).ok().flatten() {
-            if let Ok(mut thread) = serde_json::from_slice::<Thread>(&thread_bytes) {
-                thread.last_updated = Utc::now().timestamp();
-                let updated = serde_json::to_vec(&thread).expect("Failed to serialize updated thread");
-                db.insert(thread_key, updated).ok();
+            // Neither upload nor its thumbnail is left behind
+            for dir in paths.media_dirs() {
+                assert_eq!(std::fs::read_dir(dir).unwrap().count(), 0, "{} is not empty after {}", dir, uri);
             }
         }
+        std::fs::remove_dir_all(&data_dir).ok();
+    }
 
-        Ok(HttpResponse::SeeOther()
-            .append_header(("Location", format!("/thread/{}", parent_id)))
-            .finish())
-    } else {
-        error!("Failed to insert reply into sled db");
-        Ok(HttpResponse::InternalServerError()
-            .content_type("text/html")
-            .body(render_error_page("Internal Server Error", "Failed to post reply")))
+    #[actix_web::test]
+    async fn image_extension_follows_content_not_filename() {
+        let (data_dir, paths) = temp_data_dir();
+        let state = test_state(&paths);
+        let db = state.db.clone();
+        let captcha = test_captcha(&state.captchas);
+        let app = actix_web::test::init_service(build_app(state)).await;
+
+        // A PNG whose filename and content type claim it is a JPEG
+        let png = test_png(4);
+        let body = multipart_body(&[
+            ("board", None, "text/plain", b""),
+            ("captcha", None, "text/plain", b"4"),
+            ("message", None, "text/plain", b"Renamed picture"),
+            ("media", Some("photo.jpg"), "image/jpeg", &png),
+        ]);
+        let request = multipart_request("/thread", "192.0.2.30", captcha, body).to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::SEE_OTHER);
+
+        // The stored file is named after the PNG it is, and the post links to it (through its thumbnail)
+        let stored: Vec<String> = std::fs::read_dir(&paths.image_uploads)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(stored.len(), 1);
+        assert!(stored[0].ends_with(".png"), "{} does not end in .png", stored[0]);
+        let thread: Thread = serde_json::from_slice(&db.get(thread_key(MAIN_BOARD, 1)).unwrap().unwrap()).unwrap();
+        let media_url = thread.media_url.expect("Thread has no media");
+        assert!(media_url.contains(&stored[0]), "{} does not link to {}", media_url, stored[0]);
+        std::fs::remove_dir_all(&data_dir).ok();
     }
-}
 
-// Function to fetch all replies for a given thread from the Sled database
-fn get_replies(db: &Db, parent_id: i32) -> Vec<Reply> {
-    db.scan_prefix(format!("reply_{}", parent_id).as_bytes())
-        .filter_map(|res| {
-            if let Ok((_, value)) = res {
-                serde_json::from_slice(&value).ok()
-            } else {
-                None
-            }
-        })
-        .collect::<Vec<Reply>>()
-}
+    #[test]
+    fn pagination_items_collapse_distant_pages() {
+        use PageItem::{Gap, Page};
+        assert_eq!(pagination_items(1, 1), vec![Page(1)]);
+        assert_eq!(pagination_items(2, 4), vec![Page(1), Page(2), Page(3), Page(4)]);
+        assert_eq!(pagination_items(1, 500), vec![Page(1), Page(2), Page(3), Gap, Page(500)]);
+        assert_eq!(
+            pagination_items(250, 500),
+            vec![Page(1), Gap, Page(248), Page(249), Page(250), Page(251), Page(252), Gap, Page(500)]
+        );
+        assert_eq!(pagination_items(500, 500), vec![Page(1), Gap, Page(498), Page(499), Page(500)]);
+        // A gap never hides just one page
+        assert_eq!(pagination_items(5, 9), vec![Page(1), Page(2), Page(3), Page(4), Page(5), Page(6), Page(7), Page(8), Page(9)]);
+    }
 
-// Function to count the total number of replies for a given thread
-fn count_replies(db: &Db, parent_id: i32) -> i32 {
-    db.scan_prefix(format!("reply_{}", parent_id).as_bytes()).count() as i32
+    #[test]
+    fn sideways_jpeg_is_stored_upright() {
+        // A 40x20 JPEG whose EXIF says it must be turned 90 degrees clockwise to display upright
+        let mut jpeg = std::io::Cursor::new(Vec::new());
+        image::DynamicImage::new_rgb8(40, 20).write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(90)).unwrap();
+        let jpeg = jpeg.into_inner();
+        let tiff: &[u8] = &[
+            b'M', b'M', 0, 42, 0, 0, 0, 8, // Big-endian header, first IFD at offset 8
+            0, 1, // One entry
+            0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0, // Orientation, SHORT, count 1, value 6
+            0, 0, 0, 0, // No further IFDs
+        ];
+        let mut app1 = b"Exif\0\0".to_vec();
+        app1.extend_from_slice(tiff);
+        let mut bytes = jpeg[..2].to_vec();
+        bytes.extend_from_slice(&[0xFF, 0xE1]);
+        bytes.extend_from_slice(&(app1.len() as u16 + 2).to_be_bytes());
+        bytes.extend_from_slice(&app1);
+        bytes.extend_from_slice(&jpeg[2..]);
+
+        let (data_dir, paths) = temp_data_dir();
+        let filepath = format!("{}photo.jpeg", paths.image_uploads);
+        std::fs::write(&filepath, bytes).unwrap();
+        assert_eq!(read_exif_orientation(&filepath), 6);
+
+        strip_image_metadata(&filepath, "jpeg").unwrap();
+        assert_eq!(image::image_dimensions(&filepath).unwrap(), (20, 40));
+        assert_eq!(read_exif_orientation(&filepath), 1);
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
 }
@@ -1,11 +1,36 @@
-// static/script.js
-
-document.addEventListener('DOMContentLoaded', () => {
-    const images = document.querySelectorAll('.toggle-image');
-
-    images.forEach(img => {
-        img.addEventListener('click', () => {
-            img.classList.toggle('expanded');
-        });
-    });
-});
+// static/script.js
+
+document.addEventListener('DOMContentLoaded', () => {
+    const images = document.querySelectorAll('.toggle-image');
+
+    images.forEach(img => {
+        img.addEventListener('click', () => {
+            // The first click on a spoiler only reveals it
+            if (img.classList.contains('spoiler')) {
+                img.classList.remove('spoiler');
+                return;
+            }
+            img.classList.toggle('expanded');
+        });
+    });
+
+    // Jump to the first unread reply when a thread is opened with ?after=
+    const newReplies = document.getElementById('new-replies');
+    if (newReplies && !window.location.hash) {
+        newReplies.scrollIntoView();
+    }
+
+    // Append replies to the thread as they are posted
+    const replyList = document.querySelector('.postlists[data-events]');
+    if (replyList && window.EventSource) {
+        const events = new EventSource(replyList.dataset.events);
+        events.addEventListener('reply', event => {
+            if (replyList.querySelector('.reply-post')) {
+                replyList.insertAdjacentHTML('beforeend', '<hr>');
+            } else {
+                replyList.innerHTML = '';
+            }
+            replyList.insertAdjacentHTML('beforeend', event.data);
+        });
+    }
+});